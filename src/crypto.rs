@@ -1,12 +1,15 @@
 extern crate hex;
 
 use wasm_bindgen::prelude::*;
-use std::str;
 
 use rand::rngs::OsRng;
 use rand::{CryptoRng, Rng};
 use aes_gcm_siv::{Aes128GcmSiv, Aes256GcmSiv, Key as AesKey, Nonce};
-use aes_gcm_siv::aead::{Aead, NewAead};
+use aes_gcm_siv::aead::{Aead, NewAead, Payload};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
 
 pub fn gen_nonce<T>(csprng: &mut T) -> [u8; 12] where T: CryptoRng + Rng, {
     let mut nonce = [0u8; 12];
@@ -26,56 +29,829 @@ pub fn gen_key_32<T>(csprng: &mut T) -> [u8; 32] where T: CryptoRng + Rng, {
     key
 }
 
-pub fn encrypt_custom(plaintext: &String, secret_key: &[u8]) -> String {
+/*
+ * Versioned ciphertext envelope
+ *
+ * Layout: version (1) | algorithm (1) | padding (1) | key id length (1) | key id | nonce (12) | ciphertext
+ *
+ * Version 1 envelopes have no padding byte, and were never padded.
+ *
+ * The header is authenticated as associated data, followed by whatever context the caller
+ * wants to bind the ciphertext to (record id, field name, ...). An envelope is encoded as a single base64
+ * string, which never contains a ':' and can therefore be told apart from the legacy
+ * `base64(nonce):base64(ciphertext)` format.
+ */
+pub const ENVELOPE_VERSION: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    Aes128GcmSiv = 1,
+    Aes256GcmSiv = 2
+}
+
+impl Algorithm {
+    pub fn for_key(secret_key: &[u8]) -> Result<Self, Error> {
+        match secret_key.len() {
+            16 => Ok(Algorithm::Aes128GcmSiv),
+            32 => Ok(Algorithm::Aes256GcmSiv),
+            _ => Err(Error::Malformed("Invalid key length".to_string()))
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::Aes128GcmSiv => "aes-128-gcm-siv",
+            Algorithm::Aes256GcmSiv => "aes-256-gcm-siv"
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Self, Error> {
+        match byte {
+            1 => Ok(Algorithm::Aes128GcmSiv),
+            2 => Ok(Algorithm::Aes256GcmSiv),
+            _ => Err(Error::Malformed("Unknown algorithm".to_string()))
+        }
+    }
+}
+
+/*
+ * How the plaintext is padded before encryption, to hide its exact length
+ *
+ * `Block` is ISO/IEC 7816-4 padding: a 0x80 terminator followed by zeros, up to the next
+ * multiple of PADDING_BLOCK_SIZE.
+ */
+pub const PADDING_BLOCK_SIZE: usize = 160;
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Padding {
+    None = 0,
+    Block = 1
+}
+
+impl Default for Padding {
+    fn default() -> Self {
+        Padding::None
+    }
+}
+
+impl Padding {
+    fn from_byte(byte: u8) -> Result<Self, Error> {
+        match byte {
+            0 => Ok(Padding::None),
+            1 => Ok(Padding::Block),
+            _ => Err(Error::Malformed("Unknown padding".to_string()))
+        }
+    }
+
+    pub fn pad(&self, plaintext: &[u8]) -> Vec<u8> {
+        let mut padded = plaintext.to_vec();
+
+        if let Padding::Block = self {
+            padded.push(0x80);
+            padded.resize((padded.len() + PADDING_BLOCK_SIZE - 1) / PADDING_BLOCK_SIZE * PADDING_BLOCK_SIZE, 0);
+        }
+
+        padded
+    }
+
+    pub fn unpad(&self, mut padded: Vec<u8>) -> Result<Vec<u8>, Error> {
+        if let Padding::Block = self {
+            match padded.iter().rposition(|byte| *byte != 0) {
+                Some(terminator) if padded[terminator] == 0x80 => padded.truncate(terminator),
+                _ => return Err(Error::Malformed("Invalid padding".to_string()))
+            }
+        }
+
+        Ok(padded)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Envelope {
+    pub version: u8,
+    pub algorithm: Algorithm,
+    pub padding: Padding,
+    pub key_id: Option<String>,
+    pub nonce: [u8; 12],
+    pub ciphertext: Vec<u8>
+}
+
+impl Envelope {
+    fn header(version: u8, algorithm: Algorithm, padding: Padding, key_id: Option<&str>) -> Result<Vec<u8>, Error> {
+        let key_id = key_id.unwrap_or("").as_bytes();
+
+        if key_id.len() > u8::MAX as usize {
+            return Err(Error::Malformed("Key id is too long".to_string()));
+        }
+
+        let mut header = vec![version, algorithm as u8];
+        if version > 1 {
+            header.push(padding as u8);
+        }
+        header.push(key_id.len() as u8);
+        header.extend_from_slice(key_id);
+
+        Ok(header)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut bytes = Envelope::header(self.version, self.algorithm, self.padding, self.key_id.as_deref())?;
+        bytes.extend_from_slice(&self.nonce);
+        bytes.extend_from_slice(&self.ciphertext);
+
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let malformed = || Error::Malformed("Invalid envelope".to_string());
+
+        let (version, algorithm, padding, key_id_len, rest) = match bytes {
+            [1, algorithm, key_id_len, rest @ ..] => (1, Algorithm::from_byte(*algorithm)?, Padding::None, *key_id_len as usize, rest),
+            [ENVELOPE_VERSION, algorithm, padding, key_id_len, rest @ ..] => (ENVELOPE_VERSION, Algorithm::from_byte(*algorithm)?, Padding::from_byte(*padding)?, *key_id_len as usize, rest),
+            [1, ..] | [ENVELOPE_VERSION, ..] => return Err(malformed()),
+            [version, ..] => return Err(Error::Malformed(format!("Unsupported envelope version {}", version))),
+            _ => return Err(malformed())
+        };
+
+        if rest.len() < key_id_len + 12 {
+            return Err(malformed());
+        }
+
+        let (key_id, rest) = rest.split_at(key_id_len);
+        let (nonce, ciphertext) = rest.split_at(12);
+
+        let key_id = match key_id_len {
+            0 => None,
+            _ => Some(String::from_utf8(key_id.to_vec()).map_err(|_| malformed())?)
+        };
+
+        let mut envelope_nonce = [0u8; 12];
+        envelope_nonce.copy_from_slice(nonce);
+
+        Ok(Envelope { version, algorithm, padding, key_id, nonce: envelope_nonce, ciphertext: ciphertext.to_vec() })
+    }
+
+    pub fn encode(&self) -> Result<String, Error> {
+        Ok(base64::encode(self.to_bytes()?))
+    }
+
+    pub fn decode(ciphertext: &str) -> Result<Self, Error> {
+        Envelope::from_bytes(&decode_ciphertext(ciphertext)?)
+    }
+}
+
+fn aead_encrypt(algorithm: Algorithm, secret_key: &[u8], nonce: &[u8], payload: Payload) -> Result<Vec<u8>, Error> {
+    let key = AesKey::from_slice(secret_key);
+
+    match algorithm {
+        Algorithm::Aes128GcmSiv => Aes128GcmSiv::new(key).encrypt(Nonce::from_slice(nonce), payload),
+        Algorithm::Aes256GcmSiv => Aes256GcmSiv::new(key).encrypt(Nonce::from_slice(nonce), payload)
+    }.map_err(|_| Error::Malformed("Encryption failure".to_string()))
+}
+
+fn aead_decrypt(algorithm: Algorithm, secret_key: &[u8], nonce: &[u8], payload: Payload) -> Result<Vec<u8>, Error> {
+    if Algorithm::for_key(secret_key)? != algorithm {
+        return Err(Error::DecryptFailed("Key does not match the algorithm".to_string()));
+    }
+
+    let key = AesKey::from_slice(secret_key);
+
+    match algorithm {
+        Algorithm::Aes128GcmSiv => Aes128GcmSiv::new(key).decrypt(Nonce::from_slice(nonce), payload),
+        Algorithm::Aes256GcmSiv => Aes256GcmSiv::new(key).decrypt(Nonce::from_slice(nonce), payload)
+    }.map_err(|_| Error::DecryptFailed("decryption failure!".to_string()))
+}
+
+/*
+ * Encrypt into an envelope, the algorithm follows from the key length
+ */
+pub fn seal_envelope(plaintext: &[u8], secret_key: &[u8], key_id: Option<&str>, aad: &[u8]) -> Result<Envelope, Error> {
+    seal_envelope_with_padding(plaintext, secret_key, key_id, Padding::None, aad)
+}
+
+pub fn seal_envelope_with_padding(plaintext: &[u8], secret_key: &[u8], key_id: Option<&str>, padding: Padding, aad: &[u8]) -> Result<Envelope, Error> {
     let mut csprng = OsRng;
 
+    let algorithm = Algorithm::for_key(secret_key)?;
     let nonce = gen_nonce(&mut csprng);
+    let mut header = Envelope::header(ENVELOPE_VERSION, algorithm, padding, key_id)?;
+    header.extend_from_slice(aad);
+
+    let ciphertext = aead_encrypt(algorithm, secret_key, &nonce, Payload { msg: &padding.pad(plaintext), aad: &header })?;
+
+    Ok(Envelope { version: ENVELOPE_VERSION, algorithm, padding, key_id: key_id.map(|k| k.to_string()), nonce, ciphertext })
+}
+
+pub fn open_envelope(envelope: &Envelope, secret_key: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut header = Envelope::header(envelope.version, envelope.algorithm, envelope.padding, envelope.key_id.as_deref())?;
+    header.extend_from_slice(aad);
+
+    let padded = aead_decrypt(envelope.algorithm, secret_key, &envelope.nonce, Payload { msg: &envelope.ciphertext, aad: &header })?;
+
+    envelope.padding.unpad(padded)
+}
+
+/*
+ * Encrypt into the binary envelope, the string variants below are all built on top of this
+ */
+pub fn encrypt_bytes(plaintext: &[u8], secret_key: &[u8], key_id: Option<&str>, aad: &[u8]) -> Result<Vec<u8>, Error> {
+    seal_envelope(plaintext, secret_key, key_id, aad)?.to_bytes()
+}
+
+pub fn decrypt_bytes(ciphertext: &[u8], secret_key: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+    open_envelope(&Envelope::from_bytes(ciphertext)?, secret_key, aad)
+}
+
+pub fn encrypt_custom(plaintext: &String, secret_key: &[u8]) -> Result<String, Error> {
+    Ok(base64::encode(encrypt_bytes(plaintext.as_bytes(), secret_key, None, b"")?))
+}
+
+/*
+ * Same as `encrypt_custom`, but records which key was used in the envelope
+ */
+pub fn encrypt_custom_with_key_id(plaintext: &String, secret_key: &[u8], key_id: &str) -> Result<String, Error> {
+    Ok(base64::encode(encrypt_bytes(plaintext.as_bytes(), secret_key, Some(key_id), b"")?))
+}
 
-    let ciphertext = if secret_key.len() == 16 {
-        let key = AesKey::from_slice(secret_key);
-        let cipher = Aes128GcmSiv::new(key);
-        cipher.encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes()).expect("encryption failure!")
+/*
+ * Same as `encrypt_custom_with_aad`, but hides the exact length of the plaintext
+ */
+pub fn encrypt_custom_padded(plaintext: &String, secret_key: &[u8], key_id: Option<&str>, aad: &[u8]) -> Result<String, Error> {
+    seal_envelope_with_padding(plaintext.as_bytes(), secret_key, key_id, Padding::Block, aad)?.encode()
+}
 
+/*
+ * Bind the ciphertext to some context, decryption only succeeds when given the same aad
+ */
+pub fn encrypt_custom_with_aad(plaintext: &String, secret_key: &[u8], key_id: Option<&str>, aad: &[u8]) -> Result<String, Error> {
+    Ok(base64::encode(encrypt_bytes(plaintext.as_bytes(), secret_key, key_id, aad)?))
+}
+
+/*
+ * Legacy ciphertexts were never bound to any context and are rejected here
+ */
+pub fn decrypt_custom_with_aad(ciphertext: &String, secret_key: &[u8], aad: &[u8]) -> Result<String, Error> {
+    if is_legacy(ciphertext) {
+        return Err(Error::Malformed("Legacy ciphertext has no associated data".to_string()));
+    }
+
+    into_string(decrypt_bytes(&decode_ciphertext(ciphertext)?, secret_key, aad)?)
+}
+
+/*
+ * Decrypt both envelopes and the legacy format
+ */
+pub fn decrypt_custom(ciphertext: &String, secret_key: &[u8]) -> Result<String, Error> {
+    let plaintext = if is_legacy(ciphertext) {
+        decrypt_legacy(ciphertext, secret_key)?
     } else {
-        let key = AesKey::from_slice(secret_key);
-        let cipher = Aes256GcmSiv::new(key);
-        cipher.encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes()).expect("encryption failure!")
+        decrypt_bytes(&decode_ciphertext(ciphertext)?, secret_key, b"")?
     };
 
-    format!("{}:{}", base64::encode(nonce), base64::encode(ciphertext))
+    into_string(plaintext)
 }
 
-pub fn decrypt_custom(ciphertext: &String, secret_key: &[u8]) -> Result<String, String> {
-    let split: Vec<&str> = ciphertext.split(':').collect();
-    let nonce: Vec<u8> = base64::decode(split[0]).unwrap();
-    let bytes = base64::decode(split[1]).unwrap();
+fn decode_ciphertext(ciphertext: &str) -> Result<Vec<u8>, Error> {
+    base64::decode(ciphertext).map_err(|_| Error::Malformed("Invalid ciphertext".to_string()))
+}
 
-    if secret_key.len() == 16 {
-        let key = AesKey::from_slice(secret_key);
-        let cipher = Aes128GcmSiv::new(key);
+fn into_string(plaintext: Vec<u8>) -> Result<String, Error> {
+    String::from_utf8(plaintext).map_err(|_| Error::Malformed("Plaintext is not valid utf-8".to_string()))
+}
+
+/*
+ * Ciphertexts from before the envelope was introduced: `base64(nonce):base64(ciphertext)`
+ */
+pub fn is_legacy(ciphertext: &str) -> bool {
+    ciphertext.contains(':')
+}
+
+fn decrypt_legacy(ciphertext: &str, secret_key: &[u8]) -> Result<Vec<u8>, Error> {
+    let (nonce, bytes) = match ciphertext.split_once(':') {
+        Some((nonce, bytes)) => (
+            base64::decode(nonce).map_err(|_| Error::Malformed("Invalid nonce".to_string()))?,
+            base64::decode(bytes).map_err(|_| Error::Malformed("Invalid ciphertext".to_string()))?
+        ),
+        None => return Err(Error::Malformed("Invalid ciphertext".to_string()))
+    };
+
+    if nonce.len() != 12 {
+        return Err(Error::Malformed("Invalid nonce".to_string()));
+    }
 
-        match cipher.decrypt(Nonce::from_slice(&nonce), bytes.as_ref()) {
-            Ok(plaintext) => Ok(str::from_utf8(&plaintext).unwrap().to_string()),
-            Err(_) => Err("decryption failure!".to_string())
+    aead_decrypt(Algorithm::for_key(secret_key)?, secret_key, &nonce, Payload { msg: &bytes, aad: b"" })
+}
+
+/*
+ * Re-encrypt a legacy ciphertext into an envelope, optionally binding it to some context
+ * while at it. Envelopes are returned as is.
+ */
+pub fn migrate_custom(ciphertext: &String, secret_key: &[u8], key_id: Option<&str>, aad: &[u8]) -> Result<String, Error> {
+    if !is_legacy(ciphertext) {
+        return Ok(ciphertext.clone());
+    }
+
+    seal_envelope(&decrypt_legacy(ciphertext, secret_key)?, secret_key, key_id, aad)?.encode()
+}
+
+/*
+ * STREAM construction for chunked encryption of large payloads
+ *
+ * Header: version (1) | algorithm (1) | key id length (1) | key id | chunk size (4) | nonce prefix (7)
+ *
+ * The plaintext is split into chunks of a fixed size, each encrypted with the nonce
+ * `prefix | counter (4, big endian) | last chunk flag (1)` and the header as associated data.
+ * Reordered chunks fail on the counter, a truncated stream fails because its final chunk was
+ * never flagged as such. The last chunk may be empty.
+ */
+pub const STREAM_VERSION: u8 = 1;
+pub const STREAM_CHUNK_SIZE: u32 = 65536;
+
+/*
+ * The chunk size comes from the header, which is only authenticated along with the first
+ * chunk. Cap it so that a crafted header cannot make the decryptor buffer without bound.
+ */
+pub const STREAM_MAX_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+
+const TAG_SIZE: usize = 16;
+
+fn stream_nonce(prefix: &[u8; 7], counter: u32, last: bool) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..7].copy_from_slice(prefix);
+    nonce[7..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+
+    nonce
+}
+
+/*
+ * Feed plaintext through `update` and call `finish` at the end of the stream. The output of
+ * every call must be concatenated in order, starting with the header.
+ */
+#[wasm_bindgen]
+pub struct StreamEncryptor {
+    secret_key: Vec<u8>,
+    algorithm: Algorithm,
+    header: Vec<u8>,
+    nonce_prefix: [u8; 7],
+    chunk_size: usize,
+    counter: u32,
+    buffer: Vec<u8>,
+    header_sent: bool,
+    finished: bool
+}
+
+impl StreamEncryptor {
+    pub fn with_key(secret_key: &[u8], key_id: Option<&str>, chunk_size: u32) -> Result<Self, Error> {
+        let mut csprng = OsRng;
+
+        if chunk_size == 0 || chunk_size > STREAM_MAX_CHUNK_SIZE {
+            return Err(Error::Malformed("Invalid chunk size".to_string()));
         }
 
-    } else {
-        let key = AesKey::from_slice(secret_key);
-        let cipher = Aes256GcmSiv::new(key);
+        let algorithm = Algorithm::for_key(secret_key)?;
+        let mut nonce_prefix = [0u8; 7];
+        csprng.fill(&mut nonce_prefix);
+
+        let mut header = Envelope::header(STREAM_VERSION, algorithm, Padding::None, key_id)?;
+        header.extend_from_slice(&chunk_size.to_be_bytes());
+        header.extend_from_slice(&nonce_prefix);
+
+        Ok(StreamEncryptor {
+            secret_key: secret_key.to_vec(),
+            algorithm,
+            header,
+            nonce_prefix,
+            chunk_size: chunk_size as usize,
+            counter: 0,
+            buffer: vec![],
+            header_sent: false,
+            finished: false
+        })
+    }
+
+    pub fn push(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        if self.finished {
+            return Err(Error::Malformed("Stream is already finished".to_string()));
+        }
+
+        let mut output = self.take_header();
+        self.buffer.extend_from_slice(plaintext);
+
+        // Always hold on to the remainder, only `finalize` knows which chunk is the last one
+        while self.buffer.len() > self.chunk_size {
+            let chunk: Vec<u8> = self.buffer.drain(..self.chunk_size).collect();
+            output.extend(self.encrypt_chunk(&chunk, false)?);
+        }
+
+        Ok(output)
+    }
+
+    pub fn finalize(&mut self) -> Result<Vec<u8>, Error> {
+        if self.finished {
+            return Err(Error::Malformed("Stream is already finished".to_string()));
+        }
+
+        let mut output = self.take_header();
+        let chunk = std::mem::take(&mut self.buffer);
+
+        output.extend(self.encrypt_chunk(&chunk, true)?);
+        self.finished = true;
+
+        Ok(output)
+    }
+
+    fn take_header(&mut self) -> Vec<u8> {
+        if self.header_sent {
+            return vec![];
+        }
+
+        self.header_sent = true;
+        self.header.clone()
+    }
+
+    fn encrypt_chunk(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>, Error> {
+        let nonce = stream_nonce(&self.nonce_prefix, self.counter, last);
+        let ciphertext = aead_encrypt(self.algorithm, &self.secret_key, &nonce, Payload { msg: chunk, aad: &self.header })?;
+
+        self.counter = self.counter.checked_add(1).ok_or_else(|| Error::Malformed("Stream is too long".to_string()))?;
+
+        Ok(ciphertext)
+    }
+}
+
+#[wasm_bindgen]
+impl StreamEncryptor {
+    #[wasm_bindgen(constructor)]
+    pub fn new(secret_key: String) -> Result<StreamEncryptor, JsValue> {
+        Ok(StreamEncryptor::with_key(&decode_key(&secret_key)?, None, STREAM_CHUNK_SIZE)?)
+    }
+
+    pub fn update(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, JsValue> {
+        Ok(self.push(plaintext)?)
+    }
+
+    pub fn finish(&mut self) -> Result<Vec<u8>, JsValue> {
+        Ok(self.finalize()?)
+    }
+}
+
+/*
+ * Counterpart of `StreamEncryptor`, ciphertext may be fed in arbitrarily sized pieces. Only
+ * after `finish` succeeds is the stream known to be complete.
+ */
+#[wasm_bindgen]
+pub struct StreamDecryptor {
+    secret_key: Vec<u8>,
+    algorithm: Option<Algorithm>,
+    header: Vec<u8>,
+    key_id: Option<String>,
+    nonce_prefix: [u8; 7],
+    chunk_size: usize,
+    counter: u32,
+    buffer: Vec<u8>,
+    finished: bool
+}
+
+impl StreamDecryptor {
+    pub fn with_key(secret_key: &[u8]) -> Result<Self, Error> {
+        Algorithm::for_key(secret_key)?;
 
-        match cipher.decrypt(Nonce::from_slice(&nonce), bytes.as_ref()) {
-            Ok(plaintext) => Ok(str::from_utf8(&plaintext).unwrap().to_string()),
-            Err(_) => Err("decryption failure!".to_string())
+        Ok(StreamDecryptor {
+            secret_key: secret_key.to_vec(),
+            algorithm: None,
+            header: vec![],
+            key_id: None,
+            nonce_prefix: [0u8; 7],
+            chunk_size: 0,
+            counter: 0,
+            buffer: vec![],
+            finished: false
+        })
+    }
+
+    pub fn push(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+        if self.finished {
+            return Err(Error::Malformed("Stream is already finished".to_string()));
         }
+
+        self.buffer.extend_from_slice(ciphertext);
+
+        let algorithm = match self.algorithm {
+            Some(algorithm) => algorithm,
+            None => match self.read_header()? {
+                Some(algorithm) => algorithm,
+                None => return Ok(vec![])
+            }
+        };
+
+        let mut output = vec![];
+
+        // A full chunk can only be decrypted once we know there is more to come
+        while self.buffer.len() > self.chunk_size + TAG_SIZE {
+            let chunk: Vec<u8> = self.buffer.drain(..self.chunk_size + TAG_SIZE).collect();
+            output.extend(self.decrypt_chunk(algorithm, &chunk, false)?);
+        }
+
+        Ok(output)
     }
+
+    pub fn finalize(&mut self) -> Result<Vec<u8>, Error> {
+        if self.finished {
+            return Err(Error::Malformed("Stream is already finished".to_string()));
+        }
+
+        let algorithm = self.algorithm.ok_or_else(|| Error::DecryptFailed("Stream was truncated".to_string()))?;
+
+        if self.buffer.len() < TAG_SIZE {
+            return Err(Error::DecryptFailed("Stream was truncated".to_string()));
+        }
+
+        let chunk = std::mem::take(&mut self.buffer);
+        let output = self.decrypt_chunk(algorithm, &chunk, true)?;
+        self.finished = true;
+
+        Ok(output)
+    }
+
+    /*
+     * The key id from the header, if any. Only available after the header was read.
+     */
+    pub fn key_id(&self) -> Option<String> {
+        self.key_id.clone()
+    }
+
+    fn read_header(&mut self) -> Result<Option<Algorithm>, Error> {
+        let (version, algorithm, key_id_len) = match &self.buffer[..] {
+            [version, algorithm, key_id_len, ..] => (*version, *algorithm, *key_id_len as usize),
+            _ => return Ok(None)
+        };
+
+        if version != STREAM_VERSION {
+            return Err(Error::Malformed(format!("Unsupported stream version {}", version)));
+        }
+
+        let header_len = 3 + key_id_len + 4 + 7;
+        if self.buffer.len() < header_len {
+            return Ok(None);
+        }
+
+        let algorithm = Algorithm::from_byte(algorithm)?;
+        if Algorithm::for_key(&self.secret_key)? != algorithm {
+            return Err(Error::DecryptFailed("Key does not match the algorithm".to_string()));
+        }
+
+        let header: Vec<u8> = self.buffer.drain(..header_len).collect();
+        let (key_id, rest) = header[3..].split_at(key_id_len);
+        let (chunk_size, nonce_prefix) = rest.split_at(4);
+        let chunk_size = u32::from_be_bytes([chunk_size[0], chunk_size[1], chunk_size[2], chunk_size[3]]);
+
+        if chunk_size == 0 || chunk_size > STREAM_MAX_CHUNK_SIZE {
+            return Err(Error::Malformed("Invalid chunk size".to_string()));
+        }
+
+        self.key_id = match key_id_len {
+            0 => None,
+            _ => Some(String::from_utf8(key_id.to_vec()).map_err(|_| Error::Malformed("Invalid stream header".to_string()))?)
+        };
+        self.chunk_size = chunk_size as usize;
+        self.nonce_prefix.copy_from_slice(nonce_prefix);
+
+        self.header = header;
+        self.algorithm = Some(algorithm);
+
+        Ok(Some(algorithm))
+    }
+
+    fn decrypt_chunk(&mut self, algorithm: Algorithm, chunk: &[u8], last: bool) -> Result<Vec<u8>, Error> {
+        let nonce = stream_nonce(&self.nonce_prefix, self.counter, last);
+        let plaintext = aead_decrypt(algorithm, &self.secret_key, &nonce, Payload { msg: chunk, aad: &self.header })?;
+
+        self.counter = self.counter.checked_add(1).ok_or_else(|| Error::Malformed("Stream is too long".to_string()))?;
+
+        Ok(plaintext)
+    }
+}
+
+#[wasm_bindgen]
+impl StreamDecryptor {
+    #[wasm_bindgen(constructor)]
+    pub fn new(secret_key: String) -> Result<StreamDecryptor, JsValue> {
+        Ok(StreamDecryptor::with_key(&decode_key(&secret_key)?)?)
+    }
+
+    pub fn update(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, JsValue> {
+        Ok(self.push(ciphertext)?)
+    }
+
+    pub fn finish(&mut self) -> Result<Vec<u8>, JsValue> {
+        Ok(self.finalize()?)
+    }
+}
+
+fn decode_key(secret_key: &String) -> Result<Vec<u8>, Error> {
+    hex::decode(secret_key).map_err(|_| Error::Malformed("Key is not in hex".to_string()))
+}
+
+#[wasm_bindgen]
+pub fn aes_gcm_siv_encrypt(plaintext: String, secret_key: String) -> Result<String, JsValue> {
+    Ok(encrypt_custom(&plaintext, &decode_key(&secret_key)?)?)
+}
+
+#[wasm_bindgen]
+pub fn aes_gcm_siv_decrypt(ciphertext: String, secret_key: String) -> Result<String, JsValue> {
+    Ok(decrypt_custom(&ciphertext, &decode_key(&secret_key)?)?)
+}
+
+#[wasm_bindgen]
+pub fn aes_gcm_siv_encrypt_bytes(plaintext: &[u8], secret_key: String, aad: Option<String>) -> Result<Vec<u8>, JsValue> {
+    Ok(encrypt_bytes(plaintext, &decode_key(&secret_key)?, None, aad.unwrap_or_default().as_bytes())?)
+}
+
+#[wasm_bindgen]
+pub fn aes_gcm_siv_decrypt_bytes(ciphertext: &[u8], secret_key: String, aad: Option<String>) -> Result<Vec<u8>, JsValue> {
+    Ok(decrypt_bytes(ciphertext, &decode_key(&secret_key)?, aad.unwrap_or_default().as_bytes())?)
+}
+
+#[wasm_bindgen]
+pub fn aes_gcm_siv_migrate(ciphertext: String, secret_key: String) -> Result<String, JsValue> {
+    Ok(migrate_custom(&ciphertext, &decode_key(&secret_key)?, None, b"")?)
+}
+
+#[wasm_bindgen]
+pub fn aes_gcm_siv_encrypt_with_aad(plaintext: String, secret_key: String, aad: String) -> Result<String, JsValue> {
+    Ok(encrypt_custom_with_aad(&plaintext, &decode_key(&secret_key)?, None, aad.as_bytes())?)
 }
 
 #[wasm_bindgen]
-pub fn aes_gcm_siv_encrypt(plaintext: String, secret_key: String) -> String {
-    encrypt_custom(&plaintext, &hex::decode(secret_key).unwrap())
+pub fn aes_gcm_siv_decrypt_with_aad(ciphertext: String, secret_key: String, aad: String) -> Result<String, JsValue> {
+    Ok(decrypt_custom_with_aad(&ciphertext, &decode_key(&secret_key)?, aad.as_bytes())?)
 }
 
+/*
+ * Version of the ciphertext format, 0 being the legacy format
+ */
 #[wasm_bindgen]
-pub fn aes_gcm_siv_decrypt(ciphertext: String, secret_key: String) -> String {
-    decrypt_custom(&ciphertext, &hex::decode(secret_key).unwrap()).unwrap()
+pub fn ciphertext_version(ciphertext: String) -> Result<u8, JsValue> {
+    if is_legacy(&ciphertext) {
+        return Ok(0);
+    }
+
+    Ok(Envelope::decode(&ciphertext)?.version)
+}
+
+#[wasm_bindgen]
+pub fn ciphertext_key_id(ciphertext: String) -> Result<Option<String>, JsValue> {
+    if is_legacy(&ciphertext) {
+        return Ok(None);
+    }
+
+    Ok(Envelope::decode(&ciphertext)?.key_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope() {
+        let key = gen_key_32(&mut OsRng);
+        let ciphertext = encrypt_custom_with_key_id(&"secret".to_string(), &key, "key_id").unwrap();
+        let envelope = Envelope::decode(&ciphertext).unwrap();
+
+        assert_eq!(envelope.version, ENVELOPE_VERSION);
+        assert_eq!(envelope.algorithm, Algorithm::Aes256GcmSiv);
+        assert_eq!(envelope.key_id, Some("key_id".to_string()));
+        assert_eq!("secret", decrypt_custom(&ciphertext, &key).unwrap());
+
+        // The header is authenticated
+        let mut tampered = envelope.clone();
+        tampered.key_id = Some("other".to_string());
+
+        assert!(decrypt_custom(&tampered.encode().unwrap(), &key).is_err());
+        assert!(decrypt_custom(&ciphertext, &gen_key_16(&mut OsRng)).is_err());
+    }
+
+    #[test]
+    fn test_padding() {
+        assert_eq!(Padding::Block.pad(b"").len(), PADDING_BLOCK_SIZE);
+        assert_eq!(Padding::Block.pad(&[1u8; 159]).len(), PADDING_BLOCK_SIZE);
+        assert_eq!(Padding::Block.pad(&[1u8; 160]).len(), 2 * PADDING_BLOCK_SIZE);
+        assert_eq!(Padding::Block.unpad(Padding::Block.pad(&[0u8; 10])).unwrap(), vec![0u8; 10]);
+        assert!(Padding::Block.unpad(vec![0u8; 160]).is_err());
+        assert!(Padding::Block.unpad(vec![1u8; 160]).is_err());
+
+        // Only the padded length is visible
+        let key = gen_key_32(&mut OsRng);
+        let short = encrypt_custom_padded(&"a".to_string(), &key, Some("key_id"), b"").unwrap();
+        let long = encrypt_custom_padded(&"a".repeat(100), &key, Some("key_id"), b"").unwrap();
+
+        assert_eq!(short.len(), long.len());
+        assert_eq!(Envelope::decode(&short).unwrap().padding, Padding::Block);
+        assert_eq!("a", decrypt_custom(&short, &key).unwrap());
+
+        // Version 1 envelopes have no padding byte
+        let nonce = gen_nonce(&mut OsRng);
+        let header = Envelope::header(1, Algorithm::Aes256GcmSiv, Padding::None, Some("key_id")).unwrap();
+        let ciphertext = aead_encrypt(Algorithm::Aes256GcmSiv, &key, &nonce, Payload { msg: b"secret", aad: &header }).unwrap();
+        let v1 = Envelope { version: 1, algorithm: Algorithm::Aes256GcmSiv, padding: Padding::None, key_id: Some("key_id".to_string()), nonce, ciphertext };
+
+        assert_eq!(v1.to_bytes().unwrap()[2], 6);
+        assert_eq!("secret", decrypt_custom(&v1.encode().unwrap(), &key).unwrap());
+    }
+
+    #[test]
+    fn test_legacy() {
+        let key = gen_key_16(&mut OsRng);
+        let nonce = gen_nonce(&mut OsRng);
+        let bytes = Aes128GcmSiv::new(AesKey::from_slice(&key)).encrypt(Nonce::from_slice(&nonce), &b"secret"[..]).unwrap();
+        let legacy = format!("{}:{}", base64::encode(nonce), base64::encode(bytes));
+
+        assert!(is_legacy(&legacy));
+        assert_eq!("secret", decrypt_custom(&legacy, &key).unwrap());
+
+        let migrated = migrate_custom(&legacy, &key, None, b"").unwrap();
+
+        assert!(!is_legacy(&migrated));
+        assert_eq!("secret", decrypt_custom(&migrated, &key).unwrap());
+        assert_eq!(migrated, migrate_custom(&migrated, &key, None, b"").unwrap());
+        assert!(decrypt_custom_with_aad(&legacy, &key, b"").is_err());
+    }
+
+    #[test]
+    fn test_bytes() {
+        let key = gen_key_32(&mut OsRng);
+        let plaintext = vec![0u8, 159, 146, 150];
+        let ciphertext = encrypt_bytes(&plaintext, &key, None, b"").unwrap();
+
+        assert_eq!(plaintext, decrypt_bytes(&ciphertext, &key, b"").unwrap());
+        assert!(matches!(decrypt_custom(&base64::encode(&ciphertext), &key), Err(Error::Malformed(_))));
+    }
+
+    fn stream(plaintext: &[u8], key: &[u8], chunk_size: u32) -> Vec<u8> {
+        let mut encryptor = StreamEncryptor::with_key(key, Some("key_id"), chunk_size).unwrap();
+
+        // Feed it in uneven pieces
+        let mut ciphertext = vec![];
+        for piece in plaintext.chunks(3) {
+            ciphertext.extend(encryptor.push(piece).unwrap());
+        }
+        ciphertext.extend(encryptor.finalize().unwrap());
+
+        ciphertext
+    }
+
+    fn unstream(ciphertext: &[u8], key: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut decryptor = StreamDecryptor::with_key(key)?;
+
+        let mut plaintext = vec![];
+        for piece in ciphertext.chunks(5) {
+            plaintext.extend(decryptor.push(piece)?);
+        }
+        plaintext.extend(decryptor.finalize()?);
+
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn test_stream() {
+        let key = gen_key_32(&mut OsRng);
+        let plaintext: Vec<u8> = (0..100).collect();
+
+        // Chunks: 0-31, 32-63, 64-95, 96-99 (last)
+        let ciphertext = stream(&plaintext, &key, 32);
+        let header_len = 3 + 6 + 4 + 7;
+
+        assert_eq!(plaintext, unstream(&ciphertext, &key).unwrap());
+        assert_eq!(Vec::<u8>::new(), unstream(&stream(&[], &key, 32), &key).unwrap());
+        assert!(unstream(&ciphertext, &gen_key_32(&mut OsRng)).is_err());
+
+        // Drop the last chunk
+        let truncated = &ciphertext[..header_len + 3 * 48];
+        assert!(matches!(unstream(truncated, &key), Err(Error::DecryptFailed(_))));
+
+        // Swap the first two chunks
+        let mut reordered = ciphertext[..header_len].to_vec();
+        reordered.extend(&ciphertext[header_len + 48..header_len + 96]);
+        reordered.extend(&ciphertext[header_len..header_len + 48]);
+        reordered.extend(&ciphertext[header_len + 96..]);
+        assert!(matches!(unstream(&reordered, &key), Err(Error::DecryptFailed(_))));
+
+        // Oversized chunks are refused up front
+        let mut oversized = ciphertext.clone();
+        oversized[9..13].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(unstream(&oversized, &key), Err(Error::Malformed(_))));
+        assert!(StreamEncryptor::with_key(&key, None, STREAM_MAX_CHUNK_SIZE + 1).is_err());
+    }
+
+    #[test]
+    fn test_aad() {
+        let key = gen_key_16(&mut OsRng);
+        let ciphertext = encrypt_custom_with_aad(&"secret".to_string(), &key, None, b"dataset_a/name").unwrap();
+
+        assert_eq!("secret", decrypt_custom_with_aad(&ciphertext, &key, b"dataset_a/name").unwrap());
+        assert!(decrypt_custom_with_aad(&ciphertext, &key, b"dataset_b/name").is_err());
+        assert!(decrypt_custom(&ciphertext, &key).is_err());
+    }
 }
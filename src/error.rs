@@ -0,0 +1,125 @@
+use std::fmt;
+
+use wasm_bindgen::prelude::*;
+use libsignal_protocol::SignalProtocolError;
+
+/*
+ * Crate wide error type
+ *
+ * Crosses the wasm boundary as a regular JS Error, with a stable `code` property so that
+ * callers can act on the kind of error instead of parsing the message.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    WrongPassphrase,
+    Locked,
+    NotInitialized,
+    KeyNotFound(String),
+    IdentityNotFound(String),
+    NoPreKeyBundle(String),
+    UntrustedIdentity(String),
+    DuplicatedMessage,
+    DecryptFailed(String),
+    SessionBroken(String),
+    InvalidSignature,
+    Network(String),
+    Http(u16, String),
+    MigrationRequired(String),
+    Malformed(String),
+    Protocol(String)
+}
+
+impl Error {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::WrongPassphrase => "WrongPassphrase",
+            Error::Locked => "Locked",
+            Error::NotInitialized => "NotInitialized",
+            Error::KeyNotFound(_) => "KeyNotFound",
+            Error::IdentityNotFound(_) => "IdentityNotFound",
+            Error::NoPreKeyBundle(_) => "NoPreKeyBundle",
+            Error::UntrustedIdentity(_) => "UntrustedIdentity",
+            Error::DuplicatedMessage => "DuplicatedMessage",
+            Error::DecryptFailed(_) => "DecryptFailed",
+            Error::SessionBroken(_) => "SessionBroken",
+            Error::InvalidSignature => "InvalidSignature",
+            Error::Network(_) => "Network",
+            Error::Http(_, _) => "Http",
+            Error::MigrationRequired(_) => "MigrationRequired",
+            Error::Malformed(_) => "Malformed",
+            Error::Protocol(_) => "Protocol"
+        }
+    }
+
+    /*
+     * Whether the backend could not be reached at all, rather than refusing the request. A
+     * gateway that cannot reach the backend counts as such.
+     */
+    pub fn is_unreachable(&self) -> bool {
+        matches!(self, Error::Network(_) | Error::Http(502..=504, _))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::WrongPassphrase => write!(f, "Wrong passphrase"),
+            Error::Locked => write!(f, "Keystore is locked"),
+            Error::NotInitialized => write!(f, "Protocol is not initialized"),
+            Error::KeyNotFound(id) => write!(f, "Key not found: {}", id),
+            Error::IdentityNotFound(user_id) => write!(f, "No identity key known for {}", user_id),
+            Error::NoPreKeyBundle(address) => write!(f, "No pre key bundle available for {}", address),
+            Error::UntrustedIdentity(address) => write!(f, "Untrusted identity for {}", address),
+            Error::DuplicatedMessage => write!(f, "Message was already decrypted"),
+            Error::DecryptFailed(reason) => write!(f, "Decryption failed: {}", reason),
+            Error::SessionBroken(reason) => write!(f, "Session is broken: {}", reason),
+            Error::InvalidSignature => write!(f, "Invalid signature"),
+            Error::Network(reason) => write!(f, "Network error: {}", reason),
+            Error::Http(status, request) => write!(f, "Request failed with status {}: {}", status, request),
+            Error::MigrationRequired(what) => write!(f, "{} is in the legacy format and has to be migrated first", what),
+            Error::Malformed(reason) => write!(f, "Malformed data: {}", reason),
+            Error::Protocol(reason) => write!(f, "Protocol error: {}", reason)
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<SignalProtocolError> for Error {
+    fn from(e: SignalProtocolError) -> Self {
+        match e {
+            SignalProtocolError::UntrustedIdentity(address) => Error::UntrustedIdentity(address.to_string()),
+            SignalProtocolError::DuplicatedMessage(_, _) => Error::DuplicatedMessage,
+            e => Error::Protocol(e.to_string())
+        }
+    }
+}
+
+impl From<Error> for JsValue {
+    fn from(e: Error) -> Self {
+        let error = js_sys::Error::new(&e.to_string());
+        js_sys::Reflect::set(&error, &"code".into(), &e.code().into()).unwrap();
+
+        error.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libsignal_protocol::ProtocolAddress;
+
+    #[test]
+    fn test_from_protocol_error() {
+        let address = ProtocolAddress::new("alice".to_string(), 1);
+
+        assert_eq!(Error::from(SignalProtocolError::UntrustedIdentity(address)), Error::UntrustedIdentity("alice.1".to_string()));
+        assert_eq!(Error::from(SignalProtocolError::InvalidPreKeyId).code(), "Protocol");
+        assert_eq!(Error::WrongPassphrase.code(), "WrongPassphrase");
+        assert_eq!(Error::NotInitialized.code(), "NotInitialized");
+
+        assert!(Error::Network("offline".to_string()).is_unreachable());
+        assert!(Error::Http(503, "GET /protocol/devices".to_string()).is_unreachable());
+        assert!(!Error::Http(404, "GET /protocol/devices".to_string()).is_unreachable());
+    }
+}
@@ -0,0 +1,168 @@
+extern crate base64;
+
+use std::result::Result;
+
+use rand::rngs::OsRng;
+use argon2::{password_hash::{PasswordHasher, SaltString, Output}, Argon2, Params, Algorithm, Version};
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::gen_key_16;
+use crate::error::Error;
+
+pub const KDF_VERSION: u8 = 1;
+pub const KDF_ALGORITHM: &str = "argon2id";
+
+/*
+ * Costs for new descriptors, as recommended by OWASP for Argon2id (m in KiB)
+ */
+pub const KDF_M_COST: u32 = 19456;
+pub const KDF_T_COST: u32 = 2;
+pub const KDF_P_COST: u32 = 1;
+
+/*
+ * Lowest costs accepted from the server, those of the legacy descriptor. Memory can be traded
+ * for passes, so it is their product that has to match.
+ */
+const KDF_MIN_M_COST: u32 = 4096;
+const KDF_MIN_WORK: u64 = 4096 * 4;
+
+/*
+ * How the root key is derived from the passphrase
+ *
+ * Stored server side alongside the keys, so that the costs can be raised over time. Version 0
+ * describes the keys from before the descriptor, which were salted with the email.
+ */
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct KdfDescriptor {
+    pub version: u8,
+    pub algorithm: String,
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+    pub salt: String
+}
+
+impl KdfDescriptor {
+    /*
+     * A fresh descriptor with the current costs and a random salt
+     */
+    pub fn generate() -> Result<Self, Error> {
+        let mut csprng = OsRng;
+        let salt = SaltString::b64_encode(&gen_key_16(&mut csprng)).map_err(|e| Error::Malformed(e.to_string()))?;
+
+        Ok(KdfDescriptor {
+            version: KDF_VERSION,
+            algorithm: KDF_ALGORITHM.to_string(),
+            m_cost: KDF_M_COST,
+            t_cost: KDF_T_COST,
+            p_cost: KDF_P_COST,
+            salt: salt.as_str().to_string()
+        })
+    }
+
+    pub fn legacy() -> Self {
+        KdfDescriptor {
+            version: 0,
+            algorithm: KDF_ALGORITHM.to_string(),
+            m_cost: 4096,
+            t_cost: 4,
+            p_cost: 1,
+            salt: "".to_string()
+        }
+    }
+
+    /*
+     * Refuse descriptors that are weaker than the legacy one
+     *
+     * The descriptor comes from the server, and the hashed passphrase derived with it is sent
+     * right back. A weak descriptor would get us to hand over a hash that is cheap to brute force.
+     */
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.m_cost < KDF_MIN_M_COST || (self.m_cost as u64) * (self.t_cost as u64) < KDF_MIN_WORK || self.p_cost < 1 {
+            return Err(Error::Malformed("Kdf descriptor is below the minimum costs".to_string()));
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Whether the descriptor is older or weaker than what new ones get
+     */
+    pub fn needs_upgrade(&self) -> bool {
+        self.version < KDF_VERSION || self.m_cost < KDF_M_COST || self.t_cost < KDF_T_COST
+    }
+
+    /*
+     * Derive the root key, and the hashed passphrase that is used to log in
+     *
+     * The email is only used by the legacy descriptor. Because the hashed passphrase is based
+     * on the root key, it changes whenever the root key does. Take care.
+     */
+    pub fn derive_keys(&self, email: &str, passphrase: &str) -> Result<(Output, String), Error> {
+        let argon2_error = |e: argon2::password_hash::Error| Error::Malformed(e.to_string());
+        let missing_hash = || Error::Malformed("No hash output".to_string());
+
+        if self.version > KDF_VERSION {
+            return Err(Error::Malformed(format!("Unsupported kdf version {}", self.version)));
+        }
+        if self.algorithm != KDF_ALGORITHM {
+            return Err(Error::Malformed(format!("Unsupported kdf algorithm {}", self.algorithm)));
+        }
+
+        let (salt, passphrase_salt) = match self.version {
+            0 => (SaltString::b64_encode(email.as_bytes()), SaltString::b64_encode(passphrase.as_bytes())),
+            _ => (SaltString::new(&self.salt), SaltString::new(&self.salt))
+        };
+
+        // Derive root key
+        let root_key = {
+            let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(32)).map_err(|e| Error::Malformed(e.to_string()))?;
+            let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+            argon2.hash_password(passphrase.as_bytes(), &salt.map_err(argon2_error)?).map_err(argon2_error)?.hash.ok_or_else(missing_hash)?
+        };
+
+        let hashed_passphrase = {
+            let params = Params::new(512, 1, 1, Some(32)).map_err(|e| Error::Malformed(e.to_string()))?; // ~ <100ms
+            let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+            argon2.hash_password(root_key.as_bytes(), &passphrase_salt.map_err(argon2_error)?).map_err(argon2_error)?.hash.ok_or_else(missing_hash)?
+        };
+
+        Ok((root_key, base64::encode(hashed_passphrase.as_bytes())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_derive_keys() {
+        let descriptor = KdfDescriptor { m_cost: 64, t_cost: 1, ..KdfDescriptor::generate().unwrap() };
+        let (root_key, hashed_passphrase) = descriptor.derive_keys("hello@pixelcities.io", "passphrase").unwrap();
+
+        // The email no longer plays a part
+        assert_eq!(descriptor.derive_keys("other@pixelcities.io", "passphrase").unwrap(), (root_key, hashed_passphrase));
+        assert_ne!(descriptor.derive_keys("hello@pixelcities.io", "wrong").unwrap().0, root_key);
+
+        let other = KdfDescriptor { m_cost: 64, t_cost: 1, ..KdfDescriptor::generate().unwrap() };
+        assert_ne!(other.salt, descriptor.salt);
+        assert_ne!(other.derive_keys("hello@pixelcities.io", "passphrase").unwrap().0, root_key);
+
+        assert!(descriptor.needs_upgrade());
+        assert!(KdfDescriptor::legacy().needs_upgrade());
+        assert!(!KdfDescriptor::generate().unwrap().needs_upgrade());
+
+        // Nothing weaker than the legacy descriptor is accepted
+        assert!(KdfDescriptor::legacy().validate().is_ok());
+        assert!(KdfDescriptor::generate().unwrap().validate().is_ok());
+        assert!(descriptor.validate().is_err());
+        assert!(KdfDescriptor { m_cost: 8192, t_cost: 1, ..KdfDescriptor::legacy() }.validate().is_err());
+        assert!(KdfDescriptor { p_cost: 0, ..KdfDescriptor::legacy() }.validate().is_err());
+
+        let unsupported = KdfDescriptor { version: KDF_VERSION + 1, ..descriptor.clone() };
+        assert!(matches!(unsupported.derive_keys("", "passphrase"), Err(Error::Malformed(_))));
+
+        let json = serde_json::to_string(&descriptor).unwrap();
+        assert_eq!(serde_json::from_str::<KdfDescriptor>(&json).unwrap(), descriptor);
+    }
+}
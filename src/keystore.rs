@@ -5,25 +5,30 @@ extern crate console_error_panic_hook;
 use wasm_bindgen::prelude::*;
 
 use std::sync::Arc;
+use std::rc::Rc;
 use std::cell::RefCell;
 use std::collections::hash_map::HashMap;
 
-use wasm_bindgen_futures::*;
 use web_sys::console;
-use futures_channel::oneshot;
-use js_sys::{Promise};
+use js_sys::Promise;
 
 use rand::rngs::OsRng;
-use argon2::{password_hash::{PasswordHasher, SaltString, Output}, Argon2, Params, Algorithm, Version};
+use uuid::Uuid;
+use argon2::password_hash::Output;
+use serde_json::json;
 
-use crate::utils::*;
 use crate::crypto::*;
+use crate::error::Error;
+use crate::kdf::KdfDescriptor;
+use crate::transport::{Transport, FetchTransport};
 
 pub struct KeyStoreInner {
     root_key: RefCell<Option<Output>>,
+    upgrade: RefCell<Option<KdfUpgrade>>,
+    legacy: RefCell<bool>,
     keys: RefCell<HashMap<String, String>>,
     manifest: RefCell<HashMap<String, String>>,
-    api_basepath: RefCell<String>
+    transport: Rc<dyn Transport>
 }
 
 #[wasm_bindgen]
@@ -31,45 +36,329 @@ pub struct KeyStore {
     inner: Arc<KeyStoreInner>
 }
 
+/*
+ * Keys derived with a new descriptor during unlock, applied once the passphrase turns out
+ * to be correct
+ */
+struct KdfUpgrade {
+    kdf: KdfDescriptor,
+    root_key: Output,
+    hashed_passphrase: String
+}
+
+impl KeyStoreInner {
+    /*
+     * Derive the root key as described by the stored descriptor, returns the hashed passphrase
+     *
+     * When the descriptor is due for an upgrade, the keys for a new one are derived as well.
+     * They replace the current ones in `init`, once the passphrase is known to be correct.
+     */
+    async fn open_sesame(&self, email: &str, passphrase: &str, allow_legacy: bool) -> Result<String, Error> {
+        let kdf = fetch_kdf(&self.transport, email, allow_legacy).await?;
+        let (root_key, hashed_passphrase) = kdf.derive_keys(email, passphrase)?;
+
+        let upgrade = match kdf.needs_upgrade() {
+            true => {
+                let kdf = KdfDescriptor::generate()?;
+                let (root_key, hashed_passphrase) = kdf.derive_keys(email, passphrase)?;
+
+                Some(KdfUpgrade { kdf, root_key, hashed_passphrase })
+            },
+            false => None
+        };
+
+        self.root_key.replace(Some(root_key));
+        self.upgrade.replace(upgrade);
+        self.legacy.replace(kdf.version == 0);
+
+        Ok(hashed_passphrase)
+    }
+
+    async fn get_hashed_passphrase(&self, email: &str, passphrase: &str, allow_legacy: bool) -> Result<String, Error> {
+        let kdf = fetch_kdf(&self.transport, email, allow_legacy).await?;
+
+        Ok(kdf.derive_keys(email, passphrase)?.1)
+    }
+
+    /*
+     * Re-encrypt all keys under the root key from the new descriptor, and store both
+     *
+     * Returns the rotation token and the new hashed passphrase, the app has to update the
+     * passphrase used to log in with them just like after `rotate_keys`.
+     */
+    async fn upgrade_kdf(&self) -> Result<Option<(String, String)>, Error> {
+        let mut csprng = OsRng;
+
+        let upgrade = match self.upgrade.borrow_mut().take() {
+            Some(upgrade) => upgrade,
+            None => return Ok(None)
+        };
+
+        let mut keys = HashMap::new();
+        for (key_id, ciphertext) in self.keys.borrow().iter() {
+            let plaintext = self.migrate_key(key_id, ciphertext)?;
+            keys.insert(key_id.clone(), encrypt_custom_with_aad(&plaintext, upgrade.root_key.as_bytes(), None, key_id.as_bytes())?);
+        }
+
+        let token = base64::encode(gen_nonce(&mut csprng));
+        let payload = json!({
+            "token": token,
+            "keys": keys.iter().map(|(key_id, ciphertext)| json!({"key_id": key_id, "ciphertext": ciphertext})).collect::<Vec<_>>(),
+            "kdf": upgrade.kdf
+        });
+
+        self.transport.request("POST", "/keys/rotate", Some(payload.to_string())).await?;
+
+        self.root_key.replace(Some(upgrade.root_key));
+        self.keys.replace(keys);
+        self.legacy.replace(false);
+
+        Ok(Some((token, upgrade.hashed_passphrase)))
+    }
+
+    /*
+     * Re-encrypt all keys under a root key derived from the new passphrase
+     *
+     * Returns the rotation token and the new hashed passphrase. The new root key and keys are
+     * only used once the server has stored them.
+     */
+    async fn rotate_keys(&self, email: &str, passphrase: &str) -> Result<(String, String), Error> {
+        let mut csprng = OsRng;
+
+        // A new passphrase always gets a fresh salt
+        let kdf = KdfDescriptor::generate()?;
+        let (root_key, hashed_passphrase) = kdf.derive_keys(email, passphrase)?;
+
+        let mut keys = HashMap::new();
+        for (key_id, ciphertext) in self.keys.borrow().iter() {
+            let plaintext = self.migrate_key(key_id, ciphertext)?;
+            keys.insert(key_id.clone(), encrypt_custom_with_aad(&plaintext, root_key.as_bytes(), None, key_id.as_bytes())?);
+        }
+
+        let token = base64::encode(gen_nonce(&mut csprng));
+        let payload = json!({
+            "token": token,
+            "keys": keys.iter().map(|(key_id, ciphertext)| json!({"key_id": key_id, "ciphertext": ciphertext})).collect::<Vec<_>>(),
+            "kdf": kdf
+        });
+
+        self.transport.request("POST", "/keys/rotate", Some(payload.to_string())).await?;
+
+        self.root_key.replace(Some(root_key));
+        self.keys.replace(keys);
+        self.upgrade.replace(None);
+        self.legacy.replace(false);
+
+        Ok((token, hashed_passphrase))
+    }
+
+    async fn init(&self) -> Result<(), Error> {
+        // Just get a single key at first, to test if the passphrase was correct
+        let json = self.transport.request("GET", "/keys?limit=1", None).await?;
+        let (key_id, ciphertext) = json.as_array()
+            .and_then(|keys| keys.first())
+            .and_then(|key| Some((key["key_id"].as_str()?, key["ciphertext"].as_str()?)))
+            .ok_or_else(|| Error::KeyNotFound("No keys found".to_string()))?;
+
+        // Try the current root_key, bail if it fails
+        match self.migrate_key(key_id, &ciphertext.to_string()) {
+            Err(Error::DecryptFailed(_)) => return Err(Error::WrongPassphrase),
+            Err(e) => return Err(e),
+            Ok(_) => ()
+        };
+
+        // Get all the keys
+        let json = self.transport.request("GET", "/keys", None).await?;
+
+        for key in json.as_array().ok_or_else(|| Error::Malformed("Invalid keys".to_string()))? {
+            let key_id = key["key_id"].as_str().ok_or_else(|| Error::Malformed("Invalid key".to_string()))?;
+            let ciphertext = key["ciphertext"].as_str().ok_or_else(|| Error::Malformed("Invalid key".to_string()))?;
+
+            self.keys.borrow_mut().insert(key_id.to_string(), ciphertext.to_string());
+        }
+
+        // And also the manifest
+        let json = self.transport.request("GET", "/keys/manifest", None).await?;
+
+        for (name, key_id) in json["manifest"].as_object().ok_or_else(|| Error::Malformed("Invalid manifest".to_string()))? {
+            let key_id = key_id.as_str().ok_or_else(|| Error::Malformed("Invalid manifest".to_string()))?;
+
+            self.manifest.borrow_mut().insert(name.clone(), key_id.to_string());
+        }
+
+        Ok(())
+    }
+
+    async fn create_named_key(&self, name: String, keysize: u32) -> Result<String, Error> {
+        // Generate a new key
+        let key_id = self.generate_key(keysize).await?;
+
+        // Store it in the local manifest
+        self.manifest.borrow_mut().insert(name, key_id.clone());
+        let manifest = self.manifest.borrow().clone();
+
+        // Sync the manifest, which is simply a json object of name: key_id
+        let body = json!({"manifest": manifest}).to_string();
+
+        self.transport.request("PUT", "/keys/manifest", Some(body)).await?;
+
+        Ok(key_id)
+    }
+
+    async fn add_key(&self, key_id: String, plaintext: String) -> Result<String, Error> {
+        let ciphertext = self.encrypt_key(&key_id, &plaintext)?;
+
+        self.keys.borrow_mut().insert(key_id.clone(), ciphertext.clone());
+
+        let body = json!({"ciphertext": ciphertext}).to_string();
+
+        self.transport.request("PUT", &key_path(&key_id), Some(body)).await?;
+
+        Ok(key_id)
+    }
+
+    async fn generate_key(&self, keysize: u32) -> Result<String, Error> {
+        let mut csprng = OsRng;
+
+        let key = match keysize {
+            16 => hex::encode(gen_key_16(&mut csprng)),
+            _ => hex::encode(gen_key_32(&mut csprng))
+        };
+
+        // The key id is bound to the ciphertext, so it has to be known before encrypting
+        let key_id = Uuid::new_v4().to_string();
+
+        self.add_key(key_id, key).await
+    }
+
+    /*
+     * Re-encrypt all keys that are still in the legacy ciphertext format
+     *
+     * Returns the number of keys that were migrated. Normally `init` already takes care of this
+     * while upgrading the key derivation, this is for when that failed.
+     */
+    async fn migrate_keys(&self) -> Result<u32, Error> {
+        let legacy_keys: Vec<(String, String)> = self.keys.borrow().iter()
+            .filter(|(_, ciphertext)| is_legacy(ciphertext))
+            .map(|(key_id, ciphertext)| (key_id.clone(), ciphertext.clone()))
+            .collect();
+
+        for (key_id, ciphertext) in &legacy_keys {
+            let ciphertext = self.encrypt_key(key_id, &self.migrate_key(key_id, ciphertext)?)?;
+            let body = json!({"ciphertext": ciphertext}).to_string();
+
+            self.transport.request("PUT", &key_path(key_id), Some(body)).await?;
+            self.keys.borrow_mut().insert(key_id.clone(), ciphertext);
+        }
+
+        Ok(legacy_keys.len() as u32)
+    }
+
+    pub(crate) fn get_key(&self, key_id: &str) -> Result<String, Error> {
+        let ciphertext = self.keys.borrow().get(key_id).cloned()
+            .ok_or_else(|| Error::KeyNotFound(key_id.to_string()))?;
+
+        self.decrypt_key(key_id, &ciphertext)
+    }
+
+    /*
+     * Add a key that was shared with us. Importing the same key twice is fine, but an existing
+     * key is never overwritten with different key material.
+     */
+    pub(crate) async fn import_key(&self, key_id: String, key: String) -> Result<String, Error> {
+        if self.keys.borrow().contains_key(&key_id) {
+            return match self.get_key(&key_id)? == key {
+                true => Ok(key_id),
+                false => Err(Error::Protocol(format!("Key {} already exists", key_id)))
+            };
+        }
+
+        self.add_key(key_id, key).await
+    }
+
+    /*
+     * Keys are bound to their key id, so that the server cannot swap them around
+     */
+    fn encrypt_key(&self, key_id: &str, plaintext: &String) -> Result<String, Error> {
+        let root_key = self.root_key.borrow().ok_or(Error::Locked)?;
+        encrypt_custom_with_aad(plaintext, root_key.as_bytes(), None, key_id.as_bytes())
+    }
+
+    fn decrypt_key(&self, key_id: &str, ciphertext: &String) -> Result<String, Error> {
+        let root_key = self.root_key.borrow().ok_or(Error::Locked)?;
+
+        if is_legacy(ciphertext) {
+            return Err(match *self.legacy.borrow() {
+                true => Error::MigrationRequired(format!("Key {}", key_id)),
+                false => Error::Malformed(format!("Key {} is in the legacy format, but the keys were already migrated", key_id))
+            });
+        }
+
+        decrypt_custom_with_aad(ciphertext, root_key.as_bytes(), key_id.as_bytes())
+    }
+
+    /*
+     * Decrypt a key on its way to being re-encrypted
+     *
+     * Legacy ciphertexts are not bound to their key id, so they are only accepted as long as
+     * the account still has the legacy key derivation. Re-encrypting all keys under a new
+     * descriptor (`upgrade_kdf`, `rotate_keys`) marks the account as migrated.
+     */
+    fn migrate_key(&self, key_id: &str, ciphertext: &String) -> Result<String, Error> {
+        let root_key = self.root_key.borrow().ok_or(Error::Locked)?;
+
+        match is_legacy(ciphertext) && *self.legacy.borrow() {
+            true => decrypt_custom(ciphertext, root_key.as_bytes()),
+            false => self.decrypt_key(key_id, ciphertext)
+        }
+    }
+}
+
 #[wasm_bindgen]
 impl KeyStore {
     #[wasm_bindgen(constructor)]
     pub fn new(api_basepath: JsValue) -> KeyStore {
         console_error_panic_hook::set_once();
 
-        let basepath = if !api_basepath.is_undefined() && api_basepath.is_string() {
-            api_basepath.as_string().unwrap()
-        } else {
-            option_env!("API_BASEPATH").unwrap_or("http://localhost:5000").to_string()
-        };
-
-        KeyStore { inner: Arc::new(KeyStoreInner {
-            root_key: RefCell::new(None),
-            keys: RefCell::new(HashMap::new()),
-            manifest: RefCell::new(HashMap::new()),
-            api_basepath: RefCell::new(basepath)
-        })}
+        KeyStore::with_transport(Rc::new(FetchTransport::from_js(&api_basepath)))
     }
 
-    pub fn open_sesame(&self, email: String, passphrase: String) -> String {
-        let (root_key, hashed_passphrase) = self.derive_keys(email, passphrase);
+    /*
+     * Derive the root key, resolves to the hashed passphrase
+     *
+     * The passphrase is only checked in `init`, which is also where the key derivation is
+     * upgraded when it is outdated. Accounts from before the kdf descriptor reject with
+     * MigrationRequired, unless `allow_legacy` is set. Only set it for an account that is known
+     * to predate the descriptor, as it lets the server pick the weaker legacy derivation.
+     */
+    pub fn open_sesame(&self, email: String, passphrase: String, allow_legacy: Option<bool>) -> Promise {
+        let _self = self.inner.clone();
 
-        self.inner.root_key.replace(root_key);
-        hashed_passphrase
+        wasm_bindgen_futures::future_to_promise(async move {
+            match _self.open_sesame(&email, &passphrase, allow_legacy.unwrap_or(false)).await {
+                Ok(hashed_passphrase) => Ok(hashed_passphrase.into()),
+                Err(e) => Err(e.into())
+            }
+        })
     }
 
-    pub fn get_hashed_passphrase(&self, email: String, passphrase: String) -> String {
-        let (_, hashed_passphrase) = self.derive_keys(email, passphrase);
+    pub fn get_hashed_passphrase(&self, email: String, passphrase: String, allow_legacy: Option<bool>) -> Promise {
+        let _self = self.inner.clone();
 
-        hashed_passphrase
+        wasm_bindgen_futures::future_to_promise(async move {
+            match _self.get_hashed_passphrase(&email, &passphrase, allow_legacy.unwrap_or(false)).await {
+                Ok(hashed_passphrase) => Ok(hashed_passphrase.into()),
+                Err(e) => Err(e.into())
+            }
+        })
     }
 
-    pub fn get_named_key(&self, name: String) -> String {
-        match self.inner.manifest.borrow().get(&name) {
-            Some(entry) => {
-                self.get_key(entry.clone())
-            },
-            None => panic!("No such entry")
+    pub fn get_named_key(&self, name: String) -> Result<String, JsValue> {
+        let entry = self.inner.manifest.borrow().get(&name).cloned();
+
+        match entry {
+            Some(key_id) => self.get_key(key_id),
+            None => Err(Error::KeyNotFound(name).into())
         }
     }
 
@@ -78,52 +367,34 @@ impl KeyStore {
         root_key.is_none()
     }
 
+    /*
+     * Load the keys, rejects with WrongPassphrase when they cannot be decrypted
+     *
+     * When the key derivation was upgraded, resolves to {token, hashed_passphrase} to update
+     * the passphrase used to log in with, the same as `rotate_keys`. Failing to upgrade does
+     * not fail the init, it is simply tried again on the next unlock.
+     */
     pub fn init(&self) -> Promise {
         let _self = self.inner.clone();
 
         wasm_bindgen_futures::future_to_promise(async move {
-            // Just get a single key at first, to test if the passphrase was correct
-            let json = request("GET".to_string(), format!("{}/keys?limit=1", _self.api_basepath.borrow()), None).await;
-            let key = js_sys::try_iter(&json).ok().flatten().and_then(|mut keys| keys.next());
-
-            match key {
-                Some(Ok(key)) => {
-                    let ciphertext: String = js_sys::Reflect::get(&key, &"ciphertext".into()).ok().and_then(|x| x.as_string()).unwrap_or("".to_string());
-                    let root_key = _self.root_key.borrow().unwrap();
-
-                    // Try the current root_key, reject the promise if it fails
-                    match decrypt_custom(&ciphertext, root_key.as_bytes()) {
-                        Ok(_) => {
-                            // Get all the keys
-                            let json = request("GET".to_string(), format!("{}/keys", _self.api_basepath.borrow()), None).await;
-
-                            for key in js_sys::try_iter(&json).unwrap().unwrap() {
-                                let obj = key.unwrap();
-                                let key_id: String = js_sys::Reflect::get(&obj, &"key_id".into()).unwrap().as_string().unwrap();
-                                let ciphertext: String = js_sys::Reflect::get(&obj, &"ciphertext".into()).unwrap().as_string().unwrap();
-
-                                _self.keys.borrow_mut().insert(key_id, ciphertext);
-                            }
-
-                            // And also the manifest
-                            let json = request("GET".to_string(), format!("{}/keys/manifest", _self.api_basepath.borrow()), None).await;
-                            let manifest = js_sys::Reflect::get(&json, &"manifest".into()).unwrap();
-
-                            for entry in js_sys::Object::entries(&manifest.into()).iter() {
-                                let arr: js_sys::Array = entry.into();
-
-                                let name: String = arr.get(0).as_string().unwrap();
-                                let key_id: String = arr.get(1).as_string().unwrap();
-
-                                _self.manifest.borrow_mut().insert(name, key_id);
-                            }
-
-                            Ok(JsValue::undefined())
-                        },
-                        Err(_) => Err(JsValue::undefined())
-                    }
+            if let Err(e) = _self.init().await {
+                return Err(e.into());
+            }
+
+            match _self.upgrade_kdf().await {
+                Ok(Some((token, hashed_passphrase))) => {
+                    let obj = js_sys::Object::new();
+                    js_sys::Reflect::set(&obj, &"token".into(), &token.into()).unwrap();
+                    js_sys::Reflect::set(&obj, &"hashed_passphrase".into(), &hashed_passphrase.into()).unwrap();
+
+                    Ok(obj.into())
                 },
-                _ => Err(JsValue::undefined())
+                Ok(None) => Ok(JsValue::undefined()),
+                Err(e) => {
+                    console::log_2(&"Cannot upgrade key derivation: ".into(), &e.to_string().into());
+                    Ok(JsValue::undefined())
+                }
             }
         })
     }
@@ -139,219 +410,403 @@ impl KeyStore {
         obj
     }
 
-    pub fn get_key(&self, id: String) -> String {
-        match self.inner.keys.borrow().get(&id) {
-            Some(key) => {
-                self.decrypt_key(key)
-            },
-            None => panic!("Invalid key id")
-        }
+    pub fn get_key(&self, id: String) -> Result<String, JsValue> {
+        Ok(self.inner.get_key(&id)?)
     }
 
     pub fn has_key(&self, id: String) -> bool {
         self.inner.keys.borrow().contains_key(&id)
     }
 
-    pub fn get_key_ids(&self) -> JsValue {
+    pub fn get_key_ids(&self) -> Result<JsValue, JsValue> {
         let keys: Vec<String> = self.inner.keys.borrow().keys().map(|k| k.clone()).collect();
 
-        serde_wasm_bindgen::to_value(&keys).unwrap()
+        Ok(serde_wasm_bindgen::to_value(&keys)?)
     }
 
     pub fn create_named_key(&self, name: String, keysize: u32) -> Promise {
         let _self = self.inner.clone();
-        let (tx, rx) = oneshot::channel();
-
-        let promise = self.generate_key(keysize);
-
-        spawn_local(async move {
-            // Generate a new key
-            let key_id = wasm_bindgen_futures::JsFuture::from(promise).await.unwrap().as_string().unwrap();
-
-            // Store it in the local manifest
-            _self.manifest.borrow_mut().insert(name, key_id.clone());
-            let manifest = _self.manifest.borrow().clone();
-
-            // Sync the manifest
-            //
-            // First create the body which is simply a json object of name: key_id
-            // Next, update the remote manifest
-            // We return the key_id, that was generated
-            let mut entries = vec![];
-            for (k, v) in manifest {
-                entries.push(format!("\"{}\": \"{}\"", k, v));
-            }
-
-            let body = format!("{{\"manifest\": {{ {} }} }}", entries.join(","));
 
-            request("PUT".to_string(), format!("{}/keys/manifest", _self.api_basepath.borrow()), Some(body)).await;
-
-            drop(tx.send(key_id));
-        });
-
-        let done = async move {
-            match rx.await {
+        wasm_bindgen_futures::future_to_promise(async move {
+            match _self.create_named_key(name, keysize).await {
                 Ok(key_id) => Ok(key_id.into()),
-                Err(_) => Err(JsValue::undefined()),
+                Err(e) => Err(e.into())
             }
-        };
-
-        wasm_bindgen_futures::future_to_promise(done)
+        })
     }
 
     pub fn add_key(&self, key_id: String, plaintext: String) -> Promise {
-        let ciphertext = self.encrypt_key(&plaintext);
-
-        self.inner.keys.borrow_mut().insert(key_id.clone(), ciphertext.clone());
-
-        let basepath = self.inner.api_basepath.borrow().clone();
+        let _self = self.inner.clone();
 
         wasm_bindgen_futures::future_to_promise(async move {
-            let body = format!("{{\"ciphertext\": \"{}\"}}", ciphertext);
-
-            request("PUT".to_string(), format!("{}/keys/{}", basepath, key_id), Some(body)).await;
-
-            Ok(JsValue::from_str(&key_id))
+            match _self.add_key(key_id, plaintext).await {
+                Ok(key_id) => Ok(JsValue::from_str(&key_id)),
+                Err(e) => Err(e.into())
+            }
         })
     }
 
     pub fn generate_key(&self, keysize: u32) -> Promise {
-        let mut csprng = OsRng;
-
         let _self = self.inner.clone();
-        let key = match keysize {
-            16 => hex::encode(gen_key_16(&mut csprng)),
-            _ => hex::encode(gen_key_32(&mut csprng))
-        };
-        let ciphertext = self.encrypt_key(&key);
 
-        let (tx, rx) = oneshot::channel();
+        wasm_bindgen_futures::future_to_promise(async move {
+            match _self.generate_key(keysize).await {
+                Ok(key_id) => Ok(key_id.into()),
+                Err(e) => Err(e.into())
+            }
+        })
+    }
 
-        spawn_local(async move {
-            let body = format!("{{\"ciphertext\": \"{}\"}}", ciphertext);
+    pub fn migrate_keys(&self) -> Promise {
+        let _self = self.inner.clone();
 
-            let json = request("POST".to_string(), format!("{}/keys", _self.api_basepath.borrow()), Some(body)).await;
+        wasm_bindgen_futures::future_to_promise(async move {
+            match _self.migrate_keys().await {
+                Ok(migrated) => Ok(migrated.into()),
+                Err(e) => Err(e.into())
+            }
+        })
+    }
 
-            let key_id: String = js_sys::Reflect::get(&json, &"key_id".into()).unwrap().as_string().unwrap();
-            let ciphertext: String = js_sys::Reflect::get(&json, &"ciphertext".into()).unwrap().as_string().unwrap();
+    pub fn rotate_keys(&self, email: String, passphrase: String) -> Promise {
+        let _self = self.inner.clone();
 
-            _self.keys.borrow_mut().insert(key_id.clone(), ciphertext);
+        console::log_1(&"Rotating keystore".into());
 
-            drop(tx.send(key_id));
-        });
+        wasm_bindgen_futures::future_to_promise(async move {
+            match _self.rotate_keys(&email, &passphrase).await {
+                Ok((token, hashed_passphrase)) => {
+                    let obj = js_sys::Object::new();
+                    js_sys::Reflect::set(&obj, &"token".into(), &token.into()).unwrap();
+                    js_sys::Reflect::set(&obj, &"hashed_passphrase".into(), &hashed_passphrase.into()).unwrap();
 
-        let done = async move {
-            match rx.await {
-                Ok(key_id) => Ok(key_id.into()),
-                Err(_) => Err(JsValue::undefined()),
+                    Ok(obj.into())
+                },
+                Err(e) => Err(e.into())
             }
-        };
-
-        wasm_bindgen_futures::future_to_promise(done)
+        })
     }
 
-    pub fn rotate_keys(&self, email: String, passphrase: String) -> Promise {
-        let mut csprng = OsRng;
+    pub fn encrypt_metadata(&self, key_id: String, plaintext: String) -> Result<String, JsValue> {
+        let metadata_key = self.metadata_key(key_id.clone())?;
 
-        console::log_1(&"Rotating keystore".into());
-
-        let (new_root_key, new_hashed_passphrase) = self.derive_keys(email, passphrase);
-        let old_keys = self.inner.keys.borrow().clone();
-        let token = base64::encode(gen_nonce(&mut csprng));
+        Ok(encrypt_custom_padded(&plaintext, &metadata_key[..], Some(&key_id), b"")?)
+    }
 
-        let mut batch = vec![];
+    pub fn decrypt_metadata(&self, key_id: String, ciphertext: String) -> Result<String, JsValue> {
+        let metadata_key = self.metadata_key(key_id)?;
 
-        for (key_id, ciphertext) in &old_keys {
-            let plaintext = self.decrypt_key(ciphertext);
-            let new_ciphertext = encrypt_custom(&plaintext, new_root_key.unwrap().as_bytes());
+        Ok(decrypt_custom(&ciphertext, &metadata_key[..])?)
+    }
 
-            batch.push(format!("{{\"key_id\": \"{}\", \"ciphertext\": \"{}\"}}", key_id, new_ciphertext));
-        }
+    /*
+     * Encrypt metadata bound to some context (e.g. record id and field name), it can only be
+     * decrypted again with the same aad
+     */
+    pub fn encrypt_metadata_with_aad(&self, key_id: String, plaintext: String, aad: String) -> Result<String, JsValue> {
+        let metadata_key = self.metadata_key(key_id.clone())?;
 
-        let basepath = self.inner.api_basepath.borrow().clone();
-        let payload = format!("{{\"token\": \"{}\", \"keys\": [{}]}}", token, batch.join(","));
+        Ok(encrypt_custom_padded(&plaintext, &metadata_key[..], Some(&key_id), aad.as_bytes())?)
+    }
 
-        let (tx, rx) = oneshot::channel();
+    pub fn decrypt_metadata_with_aad(&self, key_id: String, ciphertext: String, aad: String) -> Result<String, JsValue> {
+        let metadata_key = self.metadata_key(key_id)?;
 
-        spawn_local(async move {
-            request("POST".to_string(), format!("{}/keys/rotate", basepath), Some(payload)).await;
+        Ok(decrypt_custom_with_aad(&ciphertext, &metadata_key[..], aad.as_bytes())?)
+    }
 
-            drop(tx.send((token, new_hashed_passphrase)));
-        });
+    /*
+     * Binary variants of the above, the aad is optional
+     */
+    pub fn encrypt_bytes(&self, key_id: String, plaintext: &[u8], aad: Option<String>) -> Result<Vec<u8>, JsValue> {
+        let metadata_key = self.metadata_key(key_id.clone())?;
 
-        let done = async move {
-            match rx.await {
-                Ok((token, new_hashed_passphrase)) => {
-                    let obj = js_sys::Object::new();
-                    js_sys::Reflect::set(&obj, &"token".into(), &token.into()).unwrap();
-                    js_sys::Reflect::set(&obj, &"hashed_passphrase".into(), &new_hashed_passphrase.into()).unwrap();
+        Ok(seal_envelope_with_padding(plaintext, &metadata_key[..], Some(&key_id), Padding::Block, aad.unwrap_or_default().as_bytes())?.to_bytes()?)
+    }
 
-                    Ok(obj.into())
-                },
-                Err(_) => Err(JsValue::undefined()),
-            }
-        };
+    pub fn decrypt_bytes(&self, key_id: String, ciphertext: &[u8], aad: Option<String>) -> Result<Vec<u8>, JsValue> {
+        let metadata_key = self.metadata_key(key_id)?;
 
-        wasm_bindgen_futures::future_to_promise(done)
+        Ok(decrypt_bytes(ciphertext, &metadata_key[..], aad.unwrap_or_default().as_bytes())?)
     }
 
-    pub fn encrypt_metadata(&self, key_id: String, plaintext: String) -> String {
-        let metadata_key = hex::decode(self.get_key(key_id)).unwrap();
-        encrypt_custom(&plaintext, &metadata_key[..])
+    /*
+     * Chunked encryption for large payloads, e.g. from a ReadableStream
+     */
+    pub fn stream_encryptor(&self, key_id: String) -> Result<StreamEncryptor, JsValue> {
+        let metadata_key = self.metadata_key(key_id.clone())?;
+
+        Ok(StreamEncryptor::with_key(&metadata_key[..], Some(&key_id), STREAM_CHUNK_SIZE)?)
     }
 
-    pub fn decrypt_metadata(&self, key_id: String, ciphertext: String) -> String {
-        let metadata_key = hex::decode(self.get_key(key_id)).unwrap();
-        decrypt_custom(&ciphertext, &metadata_key[..]).unwrap()
+    pub fn stream_decryptor(&self, key_id: String) -> Result<StreamDecryptor, JsValue> {
+        let metadata_key = self.metadata_key(key_id)?;
+
+        Ok(StreamDecryptor::with_key(&metadata_key[..])?)
     }
 
-    fn derive_keys(&self, email: String, passphrase: String) -> (Option<Output>, String) {
-        // Derive root key
-        let root_key = {
-            let params = Params::new(4096, 4, 1, Some(32)).unwrap(); // ~ 1250ms
-            let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
-            let salt = SaltString::b64_encode(&email.as_bytes()).unwrap();
-            Some(argon2.hash_password(passphrase.as_bytes(), &salt).unwrap().hash.unwrap())
-        };
+    /*
+     * Upgrade a metadata blob from the legacy ciphertext format, envelopes are returned as is
+     */
+    pub fn migrate_metadata(&self, key_id: String, ciphertext: String) -> Result<String, JsValue> {
+        let metadata_key = self.metadata_key(key_id.clone())?;
 
-        // Because the root key is based on both the email and passphrase the
-        // hash will change when either of the two is mutated. Take care.
-        let hashed_passphrase = {
-            let params = Params::new(512, 1, 1, Some(32)).unwrap(); // ~ <100ms
-            let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+        Ok(migrate_custom(&ciphertext, &metadata_key[..], Some(&key_id), b"")?)
+    }
+}
 
-            let salt =  SaltString::b64_encode(&passphrase.as_bytes()).unwrap();
-            argon2.hash_password(root_key.unwrap().as_bytes(), &salt).unwrap().hash.unwrap()
-        };
+impl KeyStore {
+    /*
+     * Use a custom transport instead of fetch
+     */
+    pub fn with_transport(transport: Rc<dyn Transport>) -> KeyStore {
+        KeyStore { inner: Arc::new(KeyStoreInner {
+            root_key: RefCell::new(None),
+            upgrade: RefCell::new(None),
+            legacy: RefCell::new(false),
+            keys: RefCell::new(HashMap::new()),
+            manifest: RefCell::new(HashMap::new()),
+            transport
+        })}
+    }
 
-        (root_key, base64::encode(hashed_passphrase.as_bytes()))
+    pub(crate) fn inner(&self) -> Arc<KeyStoreInner> {
+        self.inner.clone()
     }
 
-    fn encrypt_key(&self, plaintext: &String) -> String {
-        let root_key = self.inner.root_key.borrow().unwrap();
-        encrypt_custom(plaintext, root_key.as_bytes())
+    fn metadata_key(&self, key_id: String) -> Result<Vec<u8>, Error> {
+        hex::decode(self.inner.get_key(&key_id)?)
+            .map_err(|_| Error::Malformed("Invalid metadata key".to_string()))
     }
+}
 
-    fn decrypt_key(&self, ciphertext: &String) -> String {
-        let root_key = self.inner.root_key.borrow().unwrap();
-        decrypt_custom(ciphertext, root_key.as_bytes()).unwrap()
+/*
+ * The descriptor is needed before logging in, so it is looked up by email. Without one, the
+ * keys are still from before the descriptor, which is only accepted when the caller allows it.
+ * Otherwise the server could downgrade any account to the legacy derivation.
+ */
+async fn fetch_kdf(transport: &Rc<dyn Transport>, email: &str, allow_legacy: bool) -> Result<KdfDescriptor, Error> {
+    let json = transport.request("GET", &format!("/keys/kdf?email={}", encode_query(email)), None).await?;
+
+    let kdf = match json.is_null() {
+        true => KdfDescriptor::legacy(),
+        false => serde_json::from_value(json).map_err(|_| Error::Malformed("Invalid kdf descriptor".to_string()))?
+    };
+
+    if kdf.version == 0 && !allow_legacy {
+        return Err(Error::MigrationRequired("Key derivation".to_string()));
     }
+    kdf.validate()?;
+
+    Ok(kdf)
+}
+
+/*
+ * Key ids can come from other users through shared keys, so they are never used as is
+ */
+fn key_path(key_id: &str) -> String {
+    format!("/keys/{}", encode_query(key_id))
+}
+
+fn encode_query(value: &str) -> String {
+    value.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b)
+    }).collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use futures_util::FutureExt;
+    use serde_json::json;
+    use crate::transport::MemoryTransport;
 
     #[test]
     fn test_key_x() {
-        let key_x = KeyStore::new();
-        key_x.open_sesame("hello@pixelcities.io".to_string(), "passphrase".to_string());
+        let key_x = KeyStore::with_transport(Rc::new(MemoryTransport::new()));
+        key_x.inner.open_sesame("hello@pixelcities.io", "passphrase", true).now_or_never().expect("sync").unwrap();
 
-        let key = key_x.encrypt_key(&"secret".to_string());
+        let key = key_x.inner.encrypt_key("a", &"secret".to_string()).unwrap();
         let output = "Bas52beOECLMh+sr:ER+eJfhHdtE6qkUhrDlVfeiOqkoevw==".to_string();
-        let decrypted = key_x.decrypt_key(&output);
+        let decrypted = key_x.inner.migrate_key("a", &output).unwrap();
 
         assert_eq!("secret", decrypted);
+        assert_eq!("secret", key_x.inner.decrypt_key("a", &key).unwrap());
+        assert!(key_x.inner.decrypt_key("b", &key).is_err());
+
+        // Legacy keys are only read to migrate them, and only until the account is migrated
+        assert!(matches!(key_x.inner.decrypt_key("a", &output), Err(Error::MigrationRequired(_))));
+
+        key_x.inner.legacy.replace(false);
+        assert!(matches!(key_x.inner.migrate_key("a", &output), Err(Error::Malformed(_))));
     }
-}
 
+    #[test]
+    fn test_init() {
+        async {
+            let transport = Rc::new(MemoryTransport::new());
+            let key_x = KeyStore::with_transport(transport.clone());
+            key_x.inner.open_sesame("hello@pixelcities.io", "passphrase", true).await.unwrap();
+
+            let ciphertext = key_x.inner.encrypt_key("a", &"secret".to_string()).unwrap();
+            transport.respond("GET", "/keys?limit=1", json!([{"key_id": "a", "ciphertext": ciphertext}]));
+            transport.respond("GET", "/keys", json!([{"key_id": "a", "ciphertext": ciphertext}]));
+            transport.respond("GET", "/keys/manifest", json!({"manifest": {"name": "a"}}));
+
+            key_x.inner.init().await.unwrap();
+
+            assert_eq!("secret", key_x.get_named_key("name".to_string()).unwrap());
+
+            // Names and key ids are escaped, both in the body and in the path
+            key_x.inner.add_key("../a\", \"x".to_string(), "other".to_string()).await.unwrap();
+            let (_, path, body) = transport.requests().pop().unwrap();
+            assert_eq!(path, "/keys/..%2Fa%22%2C%20%22x");
+            assert_eq!(serde_json::from_str::<serde_json::Value>(&body.unwrap()).unwrap().as_object().unwrap().len(), 1);
+
+            let key_id = key_x.inner.create_named_key("other\": \"a".to_string(), 16).await.unwrap();
+            let (_, _, body) = transport.requests().pop().unwrap();
+            let body: serde_json::Value = serde_json::from_str(&body.unwrap()).unwrap();
+            assert_eq!(body["manifest"]["other\": \"a"], json!(key_id));
+            assert_eq!(body["manifest"]["name"], json!("a"));
+
+            // Without a descriptor, the legacy derivation has to be asked for
+            let key_x = KeyStore::with_transport(transport.clone());
+            assert!(matches!(key_x.inner.open_sesame("hello@pixelcities.io", "passphrase", false).await, Err(Error::MigrationRequired(_))));
+            assert!(matches!(key_x.inner.get_hashed_passphrase("hello@pixelcities.io", "passphrase", false).await, Err(Error::MigrationRequired(_))));
+
+            transport.respond("GET", "/keys/kdf?email=hello%40pixelcities.io", serde_json::to_value(KdfDescriptor::legacy()).unwrap());
+            assert!(matches!(key_x.inner.open_sesame("hello@pixelcities.io", "passphrase", false).await, Err(Error::MigrationRequired(_))));
+
+            // Wrong passphrase
+            let key_x = KeyStore::with_transport(transport);
+            key_x.inner.open_sesame("hello@pixelcities.io", "wrong", true).await.unwrap();
+
+            assert_eq!(key_x.inner.init().await, Err(Error::WrongPassphrase));
+            assert!(!key_x.has_key("a".to_string()));
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn test_migrate_keys() {
+        async {
+            let transport = Rc::new(MemoryTransport::new());
+            let key_x = KeyStore::with_transport(transport.clone());
+            key_x.inner.open_sesame("hello@pixelcities.io", "passphrase", true).await.unwrap();
+
+            let legacy = "Bas52beOECLMh+sr:ER+eJfhHdtE6qkUhrDlVfeiOqkoevw==".to_string();
+            transport.respond("GET", "/keys?limit=1", json!([{"key_id": "a", "ciphertext": legacy}]));
+            transport.respond("GET", "/keys", json!([{"key_id": "a", "ciphertext": legacy}]));
+            transport.respond("GET", "/keys/manifest", json!({"manifest": {}}));
+
+            key_x.inner.init().await.unwrap();
+
+            assert_eq!(key_x.inner.migrate_keys().await, Ok(1));
+            assert_eq!(key_x.inner.migrate_keys().await, Ok(0));
+
+            let ciphertext = key_x.inner.keys.borrow()["a"].clone();
+
+            assert!(!is_legacy(&ciphertext));
+            assert_eq!("secret", key_x.get_key("a".to_string()).unwrap());
+            assert_eq!(transport.requests().last().unwrap().1, "/keys/a");
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn test_rotate_keys() {
+        async {
+            let transport = Rc::new(MemoryTransport::new());
+            let key_x = KeyStore::with_transport(transport.clone());
+            key_x.inner.open_sesame("hello@pixelcities.io", "passphrase", true).await.unwrap();
+
+            let legacy = "Bas52beOECLMh+sr:ER+eJfhHdtE6qkUhrDlVfeiOqkoevw==".to_string();
+            transport.respond("GET", "/keys?limit=1", json!([{"key_id": "a", "ciphertext": legacy}]));
+            transport.respond("GET", "/keys", json!([{"key_id": "a", "ciphertext": legacy}]));
+            transport.respond("GET", "/keys/manifest", json!({"manifest": {}}));
+
+            key_x.inner.init().await.unwrap();
+            key_x.inner.rotate_keys("hello@pixelcities.io", "new passphrase").await.unwrap();
+
+            // The rotated keys are used right away
+            assert_eq!("secret", key_x.get_key("a".to_string()).unwrap());
+
+            key_x.inner.add_key("b".to_string(), "other".to_string()).await.unwrap();
+            assert_eq!("other", key_x.get_key("b".to_string()).unwrap());
+
+            // And match what the server has, under the new passphrase
+            let (_, _, body) = transport.requests().into_iter().find(|(_, path, _)| path == "/keys/rotate").unwrap();
+            let body: serde_json::Value = serde_json::from_str(&body.unwrap()).unwrap();
+
+            transport.respond("GET", "/keys/kdf?email=hello%40pixelcities.io", body["kdf"].clone());
+            transport.respond("GET", "/keys?limit=1", json!([body["keys"][0].clone()]));
+            transport.respond("GET", "/keys", json!([body["keys"][0].clone(), {"key_id": "b", "ciphertext": key_x.inner.keys.borrow()["b"].clone()}]));
+
+            let key_x = KeyStore::with_transport(transport);
+            key_x.inner.open_sesame("hello@pixelcities.io", "new passphrase", false).await.unwrap();
+            key_x.inner.init().await.unwrap();
+
+            assert_eq!("secret", key_x.get_key("a".to_string()).unwrap());
+            assert_eq!("other", key_x.get_key("b".to_string()).unwrap());
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn test_upgrade_kdf() {
+        async {
+            let transport = Rc::new(MemoryTransport::new());
+            let key_x = KeyStore::with_transport(transport.clone());
+            let legacy_hash = key_x.inner.open_sesame("hello@pixelcities.io", "passphrase", true).await.unwrap();
+
+            let legacy = "Bas52beOECLMh+sr:ER+eJfhHdtE6qkUhrDlVfeiOqkoevw==".to_string();
+            transport.respond("GET", "/keys?limit=1", json!([{"key_id": "a", "ciphertext": legacy}]));
+            transport.respond("GET", "/keys", json!([{"key_id": "a", "ciphertext": legacy}]));
+            transport.respond("GET", "/keys/manifest", json!({"manifest": {}}));
+
+            key_x.inner.init().await.unwrap();
+
+            let (_, hashed_passphrase) = key_x.inner.upgrade_kdf().await.unwrap().unwrap();
+            assert_ne!(hashed_passphrase, legacy_hash);
+            assert_eq!(key_x.inner.upgrade_kdf().await, Ok(None));
+            assert_eq!("secret", key_x.get_key("a".to_string()).unwrap());
+
+            // The new descriptor is stored along with the re-encrypted keys
+            let (_, path, body) = transport.requests().pop().unwrap();
+            let body: serde_json::Value = serde_json::from_str(&body.unwrap()).unwrap();
+            assert_eq!(path, "/keys/rotate");
+
+            transport.respond("GET", "/keys/kdf?email=hello%40pixelcities.io", body["kdf"].clone());
+            transport.respond("GET", "/keys?limit=1", json!([body["keys"][0].clone()]));
+            transport.respond("GET", "/keys", body["keys"].clone());
+
+            let key_x = KeyStore::with_transport(transport.clone());
+            assert_eq!(key_x.inner.open_sesame("hello@pixelcities.io", "passphrase", false).await.unwrap(), hashed_passphrase);
+            assert!(key_x.inner.upgrade.borrow().is_none());
+
+            key_x.inner.init().await.unwrap();
+            assert_eq!("secret", key_x.get_key("a".to_string()).unwrap());
+
+            // A legacy key is no longer accepted once migrated
+            transport.respond("GET", "/keys?limit=1", json!([{"key_id": "a", "ciphertext": legacy}]));
+
+            let key_x = KeyStore::with_transport(transport.clone());
+            key_x.inner.open_sesame("hello@pixelcities.io", "passphrase", false).await.unwrap();
+            assert!(matches!(key_x.inner.init().await, Err(Error::Malformed(_))));
+
+            // Nor can the backend talk us into a weaker key derivation
+            let mut weak = body["kdf"].clone();
+            weak["m_cost"] = json!(8);
+            transport.respond("GET", "/keys/kdf?email=hello%40pixelcities.io", weak);
+
+            let key_x = KeyStore::with_transport(transport);
+            assert!(matches!(key_x.inner.open_sesame("hello@pixelcities.io", "passphrase", false).await, Err(Error::Malformed(_))));
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+}
@@ -5,7 +5,14 @@ static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 mod keystore;
 mod protocol;
 mod crypto;
+mod error;
+mod kdf;
+mod lock;
+mod message;
+mod outbox;
+mod share;
 mod storage;
+mod transport;
 mod utils;
 
 pub use libsignal_protocol;
@@ -13,7 +20,15 @@ pub use libsignal_protocol;
 pub use {
     keystore::KeyStore,
     protocol::Protocol,
+    error::Error,
+    kdf::KdfDescriptor,
+    message::{Envelope as MessageEnvelope, Content, MessageType},
+    share::SharedKey,
     storage::PreKeyBundleSerde,
+    transport::{Transport, FetchTransport},
     crypto::*
 };
 
+#[cfg(any(test, feature = "memory-transport"))]
+pub use transport::MemoryTransport;
+
@@ -0,0 +1,168 @@
+use std::cell::{Cell, RefCell, RefMut};
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+
+/*
+ * Single threaded async lock, handed out in the order it was asked for
+ *
+ * Every call to `lock` takes a ticket right away, so operations run in the order they were
+ * started from JS, not in the order their futures happen to be polled. The value never leaves
+ * the lock: the guard only borrows it, and gives the turn to the next ticket when it is
+ * dropped. On wasm32 panics abort, so a panic while holding the lock kills the instance along
+ * with the lock. Only native builds unwind, and release it.
+ */
+pub struct Lock<T> {
+    value: RefCell<T>,
+    next_ticket: Cell<u64>,
+    serving: Cell<u64>,
+    abandoned: RefCell<BTreeSet<u64>>,
+    waiters: RefCell<Vec<Waker>>
+}
+
+pub struct LockFuture<'a, T> {
+    lock: &'a Lock<T>,
+    ticket: u64,
+    acquired: bool
+}
+
+pub struct Guard<'a, T> {
+    lock: &'a Lock<T>,
+    value: Option<RefMut<'a, T>>
+}
+
+impl<T> Lock<T> {
+    pub fn new(value: T) -> Self {
+        Lock {
+            value: RefCell::new(value),
+            next_ticket: Cell::new(0),
+            serving: Cell::new(0),
+            abandoned: RefCell::new(BTreeSet::new()),
+            waiters: RefCell::new(Vec::new())
+        }
+    }
+
+    pub fn lock(&self) -> LockFuture<'_, T> {
+        let ticket = self.next_ticket.get();
+        self.next_ticket.set(ticket + 1);
+
+        LockFuture { lock: self, ticket, acquired: false }
+    }
+
+    /*
+     * Give the turn to the next ticket that is still waiting
+     */
+    fn advance(&self) {
+        let mut serving = self.serving.get() + 1;
+        let mut abandoned = self.abandoned.borrow_mut();
+        while abandoned.remove(&serving) {
+            serving += 1;
+        }
+        self.serving.set(serving);
+
+        for waker in self.waiters.borrow_mut().drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+impl<'a, T> Future for LockFuture<'a, T> {
+    type Output = Guard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.lock.serving.get() == self.ticket {
+            self.acquired = true;
+
+            return Poll::Ready(Guard { lock: self.lock, value: Some(self.lock.value.borrow_mut()) });
+        }
+
+        self.lock.waiters.borrow_mut().push(cx.waker().clone());
+
+        Poll::Pending
+    }
+}
+
+impl<'a, T> Drop for LockFuture<'a, T> {
+    fn drop(&mut self) {
+        // A caller that gave up waiting should not hold up everyone after it
+        if !self.acquired {
+            if self.lock.serving.get() == self.ticket {
+                self.lock.advance();
+            } else {
+                self.lock.abandoned.borrow_mut().insert(self.ticket);
+            }
+        }
+    }
+}
+
+impl<'a, T> Deref for Guard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for Guard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value.as_mut().unwrap()
+    }
+}
+
+impl<'a, T> Drop for Guard<'a, T> {
+    fn drop(&mut self) {
+        self.value.take();
+        self.lock.advance();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(not(target_arch = "wasm32"))]
+    use std::panic::{self, AssertUnwindSafe};
+    use futures_util::FutureExt;
+
+    #[test]
+    fn test_lock_order() {
+        let lock = Lock::new(Vec::new());
+
+        let mut first = lock.lock().boxed_local();
+        let mut second = lock.lock().boxed_local();
+        let third = lock.lock();
+
+        // Asked for later, so it has to wait its turn
+        assert!((&mut second).now_or_never().is_none());
+        assert!(lock.lock().now_or_never().is_none());
+
+        let mut guard = (&mut first).now_or_never().expect("first");
+        guard.push(1);
+        assert!((&mut second).now_or_never().is_none());
+        drop(guard);
+
+        // Giving up on a ticket passes the turn on
+        drop(second);
+        third.now_or_never().expect("third").push(3);
+
+        assert_eq!(*lock.lock().now_or_never().expect("free"), vec![1, 3]);
+    }
+
+    // wasm32 aborts on panic, there is nothing to unwind
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn test_lock_panic() {
+        let lock = Lock::new(vec![1]);
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut guard = lock.lock().now_or_never().expect("sync");
+            guard.push(2);
+
+            panic!("mid operation");
+        }));
+
+        assert!(result.is_err());
+        assert_eq!(*lock.lock().now_or_never().expect("sync"), vec![1, 2]);
+    }
+}
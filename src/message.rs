@@ -0,0 +1,174 @@
+extern crate base64;
+
+use std::result::Result;
+
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::Padding;
+use crate::error::Error;
+
+/*
+ * Version 2 moved the timestamp from the envelope into the encrypted `Message`
+ */
+pub const MESSAGE_VERSION: u8 = 2;
+
+/*
+ * Content types that are used by the protocol itself, the app can use anything else
+ */
+pub const TEXT: &str = "text/plain";
+pub const BINARY: &str = "application/octet-stream";
+pub const SENDER_KEY: &str = "application/vnd.key-x.sender-key";
+pub const SHARED_KEY: &str = "application/vnd.key-x.shared-key+json";
+pub const SESSION_RESET: &str = "application/vnd.key-x.session-reset+json";
+
+/*
+ * The kind of Signal message in the envelope, so that it never has to be guessed
+ *
+ * For sealed messages the actual kind, and the sender, are only known after unsealing. Sender
+ * key messages are the ones that are encrypted once for a whole group.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageType {
+    PreKey,
+    Signal,
+    Sealed,
+    SenderKey
+}
+
+/*
+ * Message as it travels over the wire, serialized as json
+ *
+ * Everything but the ciphertext is visible to the server, and none of it is authenticated. The
+ * content type and timestamp are therefore part of the encrypted `Message` instead, which is
+ * padded as recorded in `padding`. Version 1 envelopes still carry a timestamp, it is ignored.
+ */
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Envelope {
+    pub version: u8,
+    #[serde(rename = "type")]
+    pub message_type: MessageType,
+    pub sender_device: u32,
+    #[serde(default)]
+    pub padding: Padding,
+    ciphertext: String
+}
+
+impl Envelope {
+    pub fn new(message_type: MessageType, sender_device: u32, padding: Padding, ciphertext: &[u8]) -> Self {
+        Envelope {
+            version: MESSAGE_VERSION,
+            message_type,
+            sender_device,
+            padding,
+            ciphertext: base64::encode(ciphertext)
+        }
+    }
+
+    pub fn ciphertext(&self) -> Result<Vec<u8>, Error> {
+        base64::decode(&self.ciphertext).map_err(|_| Error::Malformed("Message is not valid base64".to_string()))
+    }
+
+    pub fn encode(&self) -> Result<String, Error> {
+        serde_json::to_string(self).map_err(|e| Error::Malformed(e.to_string()))
+    }
+
+    pub fn decode(message: &str) -> Result<Self, Error> {
+        let envelope: Envelope = serde_json::from_str(message).map_err(|_| Error::Malformed("Invalid message envelope".to_string()))?;
+
+        if envelope.version == 0 || envelope.version > MESSAGE_VERSION {
+            return Err(Error::Malformed(format!("Unsupported message version {}", envelope.version)));
+        }
+
+        Ok(envelope)
+    }
+}
+
+/*
+ * Messages from before the envelope are bare base64, which never starts with a brace
+ */
+pub fn is_envelope(message: &str) -> bool {
+    message.starts_with('{')
+}
+
+/*
+ * The plaintext of a message, as encrypted inside the envelope
+ */
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Content {
+    pub content_type: String,
+    pub body: Vec<u8>
+}
+
+impl Content {
+    pub fn new(content_type: &str, body: Vec<u8>) -> Self {
+        Content { content_type: content_type.to_string(), body }
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>, Error> {
+        bincode::serialize(self).map_err(|_| Error::Malformed("Cannot serialize content".to_string()))
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self, Error> {
+        bincode::deserialize(data).map_err(|_| Error::Malformed("Invalid content".to_string()))
+    }
+}
+
+/*
+ * The plaintext of a version 2 envelope, or a decrypted message
+ *
+ * Version 1 envelopes hold just the `Content`, the timestamp of those is unknown.
+ */
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Message {
+    pub content: Content,
+    pub timestamp: Option<u64>
+}
+
+impl Message {
+    pub fn new(content: Content, timestamp: u64) -> Self {
+        Message { content, timestamp: Some(timestamp) }
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>, Error> {
+        bincode::serialize(self).map_err(|_| Error::Malformed("Cannot serialize message".to_string()))
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self, Error> {
+        bincode::deserialize(data).map_err(|_| Error::Malformed("Invalid message".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope() {
+        let envelope = Envelope::new(MessageType::PreKey, 2, Padding::Block, b"ciphertext");
+        let encoded = envelope.encode().unwrap();
+
+        assert!(is_envelope(&encoded));
+        assert!(!is_envelope(&base64::encode(b"ciphertext")));
+        assert!(encoded.contains("\"type\":\"pre_key\""));
+        assert_eq!(Envelope::decode(&encoded).unwrap(), envelope);
+        assert_eq!(envelope.ciphertext().unwrap(), b"ciphertext");
+
+        // Envelopes from before padding was recorded
+        let unpadded = encoded.replace(",\"padding\":\"block\"", "");
+        assert_eq!(Envelope::decode(&unpadded).unwrap().padding, Padding::None);
+
+        // The timestamp of version 1 envelopes is dropped
+        let v1 = encoded.replace("\"version\":2", "\"version\":1,\"timestamp\":100");
+        assert_eq!(Envelope::decode(&v1).unwrap().version, 1);
+
+        let unsupported = encoded.replace("\"version\":2", "\"version\":3");
+        assert_eq!(Envelope::decode(&unsupported), Err(Error::Malformed("Unsupported message version 3".to_string())));
+
+        let content = Content::new(TEXT, b"hello".to_vec());
+        assert_eq!(Content::deserialize(&content.serialize().unwrap()).unwrap(), content);
+
+        let message = Message::new(content, 100);
+        assert_eq!(Message::deserialize(&message.serialize().unwrap()).unwrap(), message);
+    }
+}
@@ -0,0 +1,157 @@
+use std::result::Result;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::crypto::{seal_envelope_with_padding, decrypt_bytes, Padding};
+use crate::error::Error;
+use crate::message::Content;
+
+/*
+ * Give up on a message after this many attempts
+ */
+pub const OUTBOX_MAX_ATTEMPTS: u32 = 10;
+
+const OUTBOX_BACKOFF: u64 = 5 * 1000;
+const OUTBOX_BACKOFF_MAX: u64 = 30 * 60 * 1000;
+
+/*
+ * A message that could not be encrypted yet, because the backend was unreachable
+ *
+ * The content is kept encrypted under the store secret, it is only opened to retry.
+ */
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct OutboxEntry {
+    pub id: String,
+    pub user_id: String,
+    pub attempts: u32,
+    pub next_attempt: u64,
+    content: Vec<u8>
+}
+
+/*
+ * Outbox
+ *
+ * Part of the synced state, so that queued messages survive a reload. Retries back off
+ * exponentially, from 5 seconds up to half an hour.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Outbox {
+    pub entries: Vec<OutboxEntry>
+}
+
+impl Outbox {
+    /*
+     * Queue a message for the user, returns the id of the entry
+     */
+    pub fn queue(&mut self, secret_key: &[u8], user_id: &str, content: &Content, now: u64) -> Result<String, Error> {
+        let id = Uuid::new_v4().to_string();
+        let sealed = seal_envelope_with_padding(&content.serialize()?, secret_key, None, Padding::Block, outbox_aad(&id).as_bytes())?;
+
+        self.entries.push(OutboxEntry {
+            id: id.clone(),
+            user_id: user_id.to_string(),
+            attempts: 1,
+            next_attempt: now + backoff(1),
+            content: sealed.to_bytes()?
+        });
+
+        Ok(id)
+    }
+
+    pub fn open(&self, secret_key: &[u8], id: &str) -> Result<Content, Error> {
+        let entry = self.get(id).ok_or_else(|| Error::KeyNotFound(id.to_string()))?;
+
+        Content::deserialize(&decrypt_bytes(&entry.content, secret_key, outbox_aad(id).as_bytes())?)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&OutboxEntry> {
+        self.entries.iter().find(|entry| entry.id == id)
+    }
+
+    pub fn remove(&mut self, id: &str) -> Option<OutboxEntry> {
+        let index = self.entries.iter().position(|entry| entry.id == id)?;
+
+        Some(self.entries.remove(index))
+    }
+
+    /*
+     * Entries that are due for a retry, oldest first
+     */
+    pub fn due(&self, now: u64) -> Vec<OutboxEntry> {
+        self.entries.iter().filter(|entry| entry.next_attempt <= now).cloned().collect()
+    }
+
+    pub fn next_attempt(&self) -> Option<u64> {
+        self.entries.iter().map(|entry| entry.next_attempt).min()
+    }
+
+    /*
+     * Push back the next attempt, returns false and drops the entry when it is out of attempts
+     */
+    pub fn retry_later(&mut self, id: &str, now: u64) -> bool {
+        match self.entries.iter_mut().find(|entry| entry.id == id) {
+            Some(entry) if entry.attempts < OUTBOX_MAX_ATTEMPTS => {
+                entry.attempts += 1;
+                entry.next_attempt = now + backoff(entry.attempts);
+
+                true
+            },
+            Some(_) => {
+                self.remove(id);
+
+                false
+            },
+            None => false
+        }
+    }
+}
+
+fn backoff(attempts: u32) -> u64 {
+    OUTBOX_BACKOFF.saturating_mul(1u64 << attempts.saturating_sub(1).min(16)).min(OUTBOX_BACKOFF_MAX)
+}
+
+/*
+ * Bind the content to its entry, so that entries cannot be swapped around
+ */
+fn outbox_aad(id: &str) -> String {
+    format!("protocol/outbox/{}", id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::TEXT;
+
+    #[test]
+    fn test_outbox() {
+        let secret_key = [1u8; 32];
+        let content = Content::new(TEXT, b"hello".to_vec());
+        let mut outbox = Outbox::default();
+
+        let id = outbox.queue(&secret_key, "bob", &content, 0).unwrap();
+        assert_eq!(outbox.open(&secret_key, &id).unwrap(), content);
+        assert!(outbox.open(&[2u8; 32], &id).is_err());
+
+        // Entries are bound to their id
+        let other = outbox.queue(&secret_key, "bob", &content, 0).unwrap();
+        outbox.entries[1].content = outbox.entries[0].content.clone();
+        assert!(outbox.open(&secret_key, &other).is_err());
+        outbox.remove(&other);
+
+        assert!(outbox.due(OUTBOX_BACKOFF - 1).is_empty());
+        assert_eq!(outbox.due(OUTBOX_BACKOFF)[0].id, id);
+
+        assert!(outbox.retry_later(&id, OUTBOX_BACKOFF));
+        assert_eq!(outbox.next_attempt(), Some(3 * OUTBOX_BACKOFF));
+
+        for attempt in 3..=OUTBOX_MAX_ATTEMPTS {
+            assert!(outbox.retry_later(&id, 0));
+            assert!(outbox.get(&id).unwrap().next_attempt <= OUTBOX_BACKOFF_MAX);
+            assert_eq!(outbox.get(&id).unwrap().attempts, attempt);
+        }
+
+        assert!(!outbox.retry_later(&id, 0));
+        assert_eq!(outbox.next_attempt(), None);
+    }
+}
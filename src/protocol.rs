@@ -3,24 +3,74 @@ extern crate console_error_panic_hook;
 
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
-use js_sys::{Promise, Date, Error};
+use js_sys::{Promise, Date};
 use web_sys::console;
 
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::rc::Rc;
 use std::cell::RefCell;
+use std::ops::{Deref, DerefMut};
 use std::convert::TryFrom;
+use std::result::Result;
 
 use rand::rngs::OsRng;
+use uuid::Uuid;
+use serde_json::json;
 use libsignal_protocol::*;
 use libsignal_protocol::{PreKeyBundle, PreKeySignalMessage, Fingerprint};
+use crate::error::Error;
+use crate::keystore::KeyStore;
+use crate::lock::{Lock, Guard};
+use crate::crypto::Padding;
+use crate::message::{self, Envelope, Content, Message, MessageType};
+use crate::share::SharedKey;
 use crate::storage::{SyncableStore, PreKeyBundleSerde};
-
-use crate::utils::*;
+use crate::transport::{Transport, FetchTransport};
 
 pub struct ProtocolInner {
-    storage: RefCell<Option<SyncableStore>>,
+    storage: Lock<Option<SyncableStore>>,
+    transport: RefCell<Option<Rc<dyn Transport>>>,
+    rotation: RefCell<RotationPolicy>,
+    pre_key_target: RefCell<u32>,
     timeout: RefCell<Option<i32>>,
-    message_ids: RefCell<Vec<String>>
+    message_ids: RefCell<Vec<String>>,
+    resets: RefCell<SessionResets>,
+    reset_callback: RefCell<Option<js_sys::Function>>,
+    trust_root: RefCell<Option<PublicKey>>,
+    sender_certificate: RefCell<Option<SenderCertificate>>,
+    sealed_sender: RefCell<bool>,
+    outbox_callback: RefCell<Option<js_sys::Function>>,
+    outbox_timeout: RefCell<Option<i32>>,
+    device_id: RefCell<Option<u32>>
+}
+
+/*
+ * Access to the storage for a single operation, only exists once the storage is initialized
+ */
+struct StorageGuard<'a>(Guard<'a, Option<SyncableStore>>);
+
+impl<'a> StorageGuard<'a> {
+    fn new(guard: Guard<'a, Option<SyncableStore>>) -> Result<Self, Error> {
+        match guard.is_some() {
+            true => Ok(StorageGuard(guard)),
+            false => Err(Error::NotInitialized)
+        }
+    }
+}
+
+impl<'a> Deref for StorageGuard<'a> {
+    type Target = SyncableStore;
+
+    fn deref(&self) -> &SyncableStore {
+        self.0.as_ref().unwrap()
+    }
+}
+
+impl<'a> DerefMut for StorageGuard<'a> {
+    fn deref_mut(&mut self) -> &mut SyncableStore {
+        self.0.as_mut().unwrap()
+    }
 }
 
 #[wasm_bindgen]
@@ -28,205 +78,2044 @@ pub struct Protocol {
     inner: Arc<ProtocolInner>
 }
 
-async fn gen_pre_key_bundles(storage: &mut SyncableStore) -> () {
-    let mut csprng = OsRng;
+impl ProtocolInner {
+    /*
+     * Use the transport that was given upfront, or default to fetch
+     */
+    fn transport(&self, api_basepath: &JsValue) -> Rc<dyn Transport> {
+        match self.transport.borrow().as_ref() {
+            Some(transport) => transport.clone(),
+            None => Rc::new(FetchTransport::from_js(api_basepath))
+        }
+    }
+
+    /*
+     * Wait for our turn with the storage, the next operation gets it when the guard is dropped
+     */
+    async fn take_storage(&self) -> Result<StorageGuard<'_>, Error> {
+        StorageGuard::new(self.storage.lock().await)
+    }
+
+    /*
+     * Hand over a freshly loaded or registered storage
+     *
+     * The device id is kept outside the lock, so that it can be read while an operation is
+     * in progress.
+     */
+    async fn install_storage(&self, storage: SyncableStore) {
+        self.device_id.replace(Some(storage.device_id));
+        *self.storage.lock().await = Some(storage);
+    }
+
+    /*
+     * Get a copy of the storage, for operations that do not mutate it
+     */
+    async fn clone_storage(&self) -> Result<SyncableStore, Error> {
+        Ok(self.take_storage().await?.clone())
+    }
+
+    fn rotation_policy(&self) -> RotationPolicy {
+        *self.rotation.borrow()
+    }
+
+    fn pre_key_target(&self) -> u32 {
+        *self.pre_key_target.borrow()
+    }
+
+    /*
+     * The certificate to seal messages with, none when sealed sender is off
+     */
+    fn sealed_sender(&self) -> Result<Option<SenderCertificate>, Error> {
+        if !*self.sealed_sender.borrow() {
+            return Ok(None);
+        }
+
+        match self.sender_certificate.borrow().clone() {
+            Some(certificate) => Ok(Some(certificate)),
+            None => Err(Error::Protocol("No sender certificate loaded".to_string()))
+        }
+    }
+
+    fn trust_root(&self) -> Result<PublicKey, Error> {
+        self.trust_root.borrow().clone().ok_or_else(|| Error::Protocol("No trust root set".to_string()))
+    }
+
+    /*
+     * Encrypt for every device of the user, returns objects of device_id: ciphertext and
+     * device_id: error. Only fails when nothing could be encrypted at all.
+     */
+    async fn encrypt(&self, user_id: &String, content: &Content) -> Result<(js_sys::Object, js_sys::Object), Error> {
+        let sender_certificate = self.sealed_sender()?;
+        let mut storage = self.take_storage().await?;
+        let maybe_ciphertexts = encrypt_for_devices(&mut storage, user_id, content, sender_certificate.as_ref()).await;
+
+        maybe_ciphertexts
+    }
+
+    /*
+     * Encrypt for several users at once, without giving the storage back in between
+     *
+     * A failure for one user does not stop the others. Returns objects of
+     * user_id: {device_id: ciphertext} and user_id: error, where the error is an object of
+     * device_id: error when only some of the devices failed
+     */
+    async fn encrypt_for_many(&self, user_ids: &[String], content: &Content) -> Result<(js_sys::Object, js_sys::Object), Error> {
+        let sender_certificate = self.sealed_sender()?;
+        let mut storage = self.take_storage().await?;
+        let ciphertexts = js_sys::Object::new();
+        let errors = js_sys::Object::new();
+
+        for (i, user_id) in user_ids.iter().enumerate() {
+            if user_ids[..i].contains(user_id) {
+                continue;
+            }
+
+            match encrypt_for_devices(&mut storage, user_id, content, sender_certificate.as_ref()).await {
+                Ok((device_ciphertexts, device_errors)) => {
+                    js_sys::Reflect::set(&ciphertexts, &user_id.into(), &device_ciphertexts.into()).unwrap();
+                    set_device_errors(&errors, user_id, device_errors);
+                },
+                Err(e) => {
+                    js_sys::Reflect::set(&errors, &user_id.into(), &e.into()).unwrap();
+                }
+            }
+        }
+
+        Ok((ciphertexts, errors))
+    }
+
+    /*
+     * Share our sender key for the group with all devices of the members
+     *
+     * Our own other devices get it too, so that they can follow the group. Just like
+     * `encrypt_for_many`, a failure for one member does not stop the others. Returns objects of
+     * user_id: {device_id: ciphertext} and user_id: error, with the same per device errors
+     */
+    async fn distribute_sender_key(&self, our_id: &String, distribution_id: Uuid, user_ids: &[String]) -> Result<(js_sys::Object, js_sys::Object), Error> {
+        let mut csprng = OsRng;
+        let sender_certificate = self.sealed_sender()?;
+        let mut storage = self.take_storage().await?;
+
+        let sender = ProtocolAddress::new(our_id.clone(), storage.device_id);
+        let distribution_message = create_sender_key_distribution_message(&sender, distribution_id, &mut storage.sender_key_store, &mut csprng, None).await?;
+        let content = Content::new(message::SENDER_KEY, distribution_message.serialized().to_vec());
+
+        let ciphertexts = js_sys::Object::new();
+        let errors = js_sys::Object::new();
+
+        for (i, user_id) in user_ids.iter().enumerate() {
+            if user_ids[..i].contains(user_id) || user_id == our_id {
+                continue;
+            }
+
+            match encrypt_for_devices(&mut storage, user_id, &content, sender_certificate.as_ref()).await {
+                Ok((device_ciphertexts, device_errors)) => {
+                    js_sys::Reflect::set(&ciphertexts, &user_id.into(), &device_ciphertexts.into()).unwrap();
+                    set_device_errors(&errors, user_id, device_errors);
+                },
+                Err(e) => {
+                    js_sys::Reflect::set(&errors, &user_id.into(), &e.into()).unwrap();
+                }
+            }
+        }
+
+        match encrypt_for_own_devices(&mut storage, our_id, &content, sender_certificate.as_ref()).await {
+            Ok((device_ciphertexts, device_errors)) => {
+                if js_sys::Object::keys(&device_ciphertexts).length() > 0 {
+                    js_sys::Reflect::set(&ciphertexts, &our_id.into(), &device_ciphertexts.into()).unwrap();
+                }
+                set_device_errors(&errors, our_id, device_errors);
+            },
+            Err(e) => {
+                js_sys::Reflect::set(&errors, &our_id.into(), &e.into()).unwrap();
+            }
+        }
+
+        Ok((ciphertexts, errors))
+    }
+
+    /*
+     * Remember the message, so that it is acknowledged with the next sync
+     */
+    fn track_message(&self, message_id: &str) {
+        if let Ok(mut message_ids) = self.message_ids.try_borrow_mut() {
+            if !message_id.is_empty() {
+                message_ids.push(message_id.to_string());
+            }
+        }
+    }
+
+    async fn decrypt(&self, address: &ProtocolAddress, message_id: String, message: &String) -> Result<Message, Error> {
+        self.track_message(&message_id);
+
+        let mut storage = self.take_storage().await?;
+        let decrypted = decrypt_message(&mut storage, address, message).await;
+        let maybe_decrypted = self.after_decrypt(&mut storage, address, &message_id, decrypted).await;
+
+        maybe_decrypted
+    }
+
+    /*
+     * Decrypt a message that has to be of the given content type
+     *
+     * The content type is only known after decrypting, so this happens on a copy of the storage
+     * that is kept only when the type matches. A message of any other type is rejected with
+     * Malformed and left untouched, it can still be decrypted with `decrypt`.
+     */
+    async fn decrypt_as(&self, address: &ProtocolAddress, message_id: String, message: &String, content_type: &str) -> Result<Message, Error> {
+        let mut storage = self.take_storage().await?;
+        let mut copy = storage.clone();
+        let decrypted = decrypt_message(&mut copy, address, message).await;
+
+        if let Ok((message, _)) = &decrypted {
+            if message.content.content_type != content_type {
+                return Err(Error::Malformed(format!("Expected a message of type {}", content_type)));
+            }
+        }
+
+        *storage = copy;
+        self.track_message(&message_id);
+
+        let maybe_decrypted = self.after_decrypt(&mut storage, address, &message_id, decrypted).await;
+
+        maybe_decrypted
+    }
+
+    /*
+     * Decrypt a sealed sender message, returns the message along with its authenticated sender
+     */
+    async fn decrypt_sealed(&self, message_id: String, message: &String) -> Result<(ProtocolAddress, Message), Error> {
+        let trust_root = self.trust_root()?;
+        self.track_message(&message_id);
+
+        let mut storage = self.take_storage().await?;
+        let maybe_decrypted = match unseal_message(&mut storage, &trust_root, message, now()).await {
+            Ok((address, ctext, envelope)) => {
+                let decrypted = match decrypt_ciphertext(&mut storage, &address, &ctext).await {
+                    Ok((plaintext, consumed)) => open_content(Some(envelope), plaintext).map(|message| (message, consumed)),
+                    Err(e) => Err(e)
+                };
+
+                self.after_decrypt(&mut storage, &address, &message_id, decrypted).await.map(|message| (address, message))
+            },
+            Err(e) => Err(e)
+        };
+
+        maybe_decrypted
+    }
+
+    /*
+     * Top up the bundles when one was used up, or try to heal a broken session
+     */
+    async fn after_decrypt(&self, storage: &mut SyncableStore, address: &ProtocolAddress, message_id: &str, decrypted: Result<(Message, bool), Error>) -> Result<Message, Error> {
+        match decrypted {
+            Ok((message, consumed)) => {
+                // A bundle was used up, failing to publish new ones should not fail the decrypt
+                if consumed {
+                    if let Err(e) = replenish_pre_key_bundles(storage, self.rotation_policy(), self.pre_key_target(), now()).await {
+                        console::log_2(&"Cannot replenish pre key bundles: ".into(), &e.to_string().into());
+                    }
+                }
+
+                Ok(message)
+            },
+            Err(Error::SessionBroken(reason)) => {
+                self.heal_session(storage, address, message_id).await;
+                Err(Error::SessionBroken(reason))
+            },
+            Err(e) => Err(e)
+        }
+    }
+
+    /*
+     * Set up a new session with the sender of a message we could not decrypt, and hand the
+     * request to resend it to the app
+     *
+     * Skipped when the app does not handle session resets, or when the sender was reset too
+     * recently, so that a hostile peer cannot make us reset over and over.
+     */
+    async fn heal_session(&self, storage: &mut SyncableStore, address: &ProtocolAddress, message_id: &str) {
+        let callback = match self.reset_callback.borrow().clone() {
+            Some(callback) => callback,
+            None => return
+        };
+
+        if !self.resets.borrow_mut().allow(address, now()) {
+            console::log_2(&"Session reset is rate limited for ".into(), &address.to_string().into());
+            return;
+        }
+
+        match reset_session(storage, address, message_id, now()).await {
+            Ok(message) => {
+                if let Err(e) = callback.call3(&JsValue::NULL, &address.name().into(), &address.device_id().into(), &message.into()) {
+                    console::log_2(&"Session reset callback failed: ".into(), &e);
+                }
+            },
+            Err(e) => console::log_2(&"Cannot reset session: ".into(), &e.to_string().into())
+        }
+    }
+
+    /*
+     * Encrypt, or queue the message in the outbox when the backend cannot be reached
+     */
+    async fn encrypt_or_queue(self: &Arc<Self>, user_id: &String, content: &Content) -> Result<Outgoing, Error> {
+        let sender_certificate = self.sealed_sender()?;
+        let mut storage = self.take_storage().await?;
+
+        let outgoing = match encrypt_for_devices(&mut storage, user_id, content, sender_certificate.as_ref()).await {
+            Ok((ciphertexts, errors)) => Outgoing::Sent(ciphertexts, errors),
+            Err(e) if e.is_unreachable() => {
+                console::log_2(&"Queued message in the outbox: ".into(), &e.to_string().into());
+                Outgoing::Queued(storage.queue_message(user_id, content, now())?)
+            },
+            Err(e) => return Err(e)
+        };
+        let next_attempt = storage.outbox.next_attempt();
+        drop(storage);
+
+        if let Outgoing::Queued(id) = &outgoing {
+            self.emit_outbox_event(&outbox_event(id, user_id, "queued", vec![]));
+            self.schedule_outbox(next_attempt);
+        }
+
+        Ok(outgoing)
+    }
+
+    /*
+     * Retry the messages in the outbox, only the ones that are due unless `all` is set
+     *
+     * Stops at the first message that still cannot reach the backend. Returns the "sent" and
+     * "failed" events by entry id, which are also handed to the outbox callback.
+     */
+    async fn flush_outbox(self: &Arc<Self>, all: bool) -> Result<js_sys::Object, Error> {
+        let sender_certificate = self.sealed_sender()?;
+        let mut storage = self.take_storage().await?;
+        let mut events = Vec::new();
+
+        let entries = storage.outbox.due(if all { u64::MAX } else { now() });
+        for entry in entries.iter() {
+            let encrypted = match storage.open_queued_message(&entry.id) {
+                Ok(content) => encrypt_for_devices(&mut storage, &entry.user_id, &content, sender_certificate.as_ref()).await,
+                Err(e) => Err(e)
+            };
+
+            match encrypted {
+                Ok((ciphertexts, errors)) => {
+                    storage.outbox.remove(&entry.id);
+                    events.push((entry.id.clone(), outbox_event(&entry.id, &entry.user_id, "sent", vec![("ciphertexts", ciphertexts.into()), ("errors", errors.into())])));
+                },
+                Err(e) if e.is_unreachable() => {
+                    if !storage.outbox.retry_later(&entry.id, now()) {
+                        events.push((entry.id.clone(), outbox_event(&entry.id, &entry.user_id, "failed", vec![("error", e.into())])));
+                    }
+                    break;
+                },
+                Err(e) => {
+                    storage.outbox.remove(&entry.id);
+                    events.push((entry.id.clone(), outbox_event(&entry.id, &entry.user_id, "failed", vec![("error", e.into())])));
+                }
+            }
+        }
+
+        if !entries.is_empty() {
+            if let Err(e) = storage.sync(None).await {
+                console::log_2(&"Cannot sync the outbox: ".into(), &e.to_string().into());
+            }
+        }
+        let next_attempt = storage.outbox.next_attempt();
+        drop(storage);
+
+        let flushed = js_sys::Object::new();
+        for (id, event) in events.iter() {
+            self.emit_outbox_event(event);
+            js_sys::Reflect::set(&flushed, &id.into(), event).unwrap();
+        }
+        self.schedule_outbox(next_attempt);
+
+        Ok(flushed)
+    }
+
+    fn emit_outbox_event(&self, event: &js_sys::Object) {
+        if let Some(callback) = self.outbox_callback.borrow().as_ref() {
+            if let Err(e) = callback.call1(&JsValue::NULL, event) {
+                console::log_2(&"Outbox callback failed: ".into(), &e);
+            }
+        }
+    }
+
+    /*
+     * Retry the outbox once the next message is due, replacing the retry that was planned
+     *
+     * Background retries hand their ciphertexts to the outbox callback only, so without one
+     * nothing is retried until `flush_outbox` is called or a callback is set.
+     */
+    fn schedule_outbox(self: &Arc<Self>, next_attempt: Option<u64>) {
+        let window = match web_sys::window() {
+            Some(window) => window,
+            None => return
+        };
+
+        if let Some(handle) = self.outbox_timeout.borrow_mut().take() {
+            window.clear_timeout_with_handle(handle);
+        }
+
+        if self.outbox_callback.borrow().is_none() {
+            return;
+        }
+
+        if let Some(next_attempt) = next_attempt {
+            let _self = self.clone();
+            let f = Closure::once_into_js(move || {
+                _self.outbox_timeout.replace(None);
+
+                let _obj = wasm_bindgen_futures::future_to_promise(async move {
+                    match _self.flush_outbox(false).await {
+                        Ok(flushed) => Ok(flushed.into()),
+                        Err(e) => Err(e.into())
+                    }
+                });
+            });
+
+            let delay = next_attempt.saturating_sub(now()).min(i32::MAX as u64) as i32;
+            // Without a timeout the entries wait for the next flush or schedule
+            match window.set_timeout_with_callback_and_timeout_and_arguments_0(f.unchecked_ref(), delay) {
+                Ok(handle) => {
+                    self.outbox_timeout.replace(Some(handle));
+                },
+                Err(e) => console::log_2(&"Cannot schedule the outbox: ".into(), &e)
+            }
+        }
+    }
+}
+
+/*
+ * A message that was either encrypted right away, or queued under the given outbox id
+ */
+enum Outgoing {
+    Sent(js_sys::Object, js_sys::Object),
+    Queued(String)
+}
+
+/*
+ * Status of an outbox entry as it is passed to JS, {id, user_id, status} with the ciphertexts
+ * and errors, or the error where applicable
+ */
+fn outbox_event(id: &str, user_id: &str, status: &str, detail: Vec<(&str, JsValue)>) -> js_sys::Object {
+    let obj = js_sys::Object::new();
+    js_sys::Reflect::set(&obj, &"id".into(), &id.into()).unwrap();
+    js_sys::Reflect::set(&obj, &"user_id".into(), &user_id.into()).unwrap();
+    js_sys::Reflect::set(&obj, &"status".into(), &status.into()).unwrap();
+
+    for (key, value) in detail {
+        js_sys::Reflect::set(&obj, &key.into(), &value).unwrap();
+    }
+
+    obj
+}
+
+/*
+ * {ciphertexts, errors} as it is passed to JS
+ */
+fn encrypted_object(ciphertexts: &js_sys::Object, errors: &js_sys::Object) -> js_sys::Object {
+    let obj = js_sys::Object::new();
+    js_sys::Reflect::set(&obj, &"ciphertexts".into(), ciphertexts).unwrap();
+    js_sys::Reflect::set(&obj, &"errors".into(), errors).unwrap();
+
+    obj
+}
+
+/*
+ * Report the devices of a user that could not be encrypted for, if any
+ */
+fn set_device_errors(errors: &js_sys::Object, user_id: &String, device_errors: js_sys::Object) {
+    if js_sys::Object::keys(&device_errors).length() > 0 {
+        js_sys::Reflect::set(errors, &user_id.into(), &device_errors.into()).unwrap();
+    }
+}
+
+/*
+ * Signed pre keys are replaced once they reach `max_age`, the previous one is kept around for
+ * `grace_period` so that PreKey messages that are still in flight can be decrypted. Both in ms.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct RotationPolicy {
+    pub max_age: u64,
+    pub grace_period: u64
+}
+
+impl RotationPolicy {
+    /*
+     * From the values as they are passed from JS. A max age that is not positive would rotate
+     * the signed pre key, and retire all bundles, on every call.
+     */
+    fn from_js(max_age: f64, grace_period: f64) -> Result<Self, Error> {
+        if !max_age.is_finite() || max_age < 1.0 || !grace_period.is_finite() || grace_period < 0.0 {
+            return Err(Error::Malformed("Invalid rotation policy".to_string()));
+        }
+
+        Ok(RotationPolicy { max_age: max_age as u64, grace_period: grace_period as u64 })
+    }
+}
+
+impl Default for RotationPolicy {
+    fn default() -> Self {
+        RotationPolicy {
+            max_age: 7 * 24 * 3600 * 1000,
+            grace_period: 30 * 24 * 3600 * 1000
+        }
+    }
+}
+
+/*
+ * Minimum time between two session resets with the same device, in ms
+ */
+const SESSION_RESET_INTERVAL: u64 = 3600 * 1000;
+
+/*
+ * When we last reset the session with each device
+ *
+ * Only kept in memory, a hostile peer cannot make us reload the state.
+ */
+#[derive(Default)]
+struct SessionResets {
+    last: HashMap<ProtocolAddress, u64>
+}
+
+impl SessionResets {
+    fn allow(&mut self, address: &ProtocolAddress, now: u64) -> bool {
+        match self.last.get(address) {
+            Some(last) if now.saturating_sub(*last) < SESSION_RESET_INTERVAL => false,
+            _ => {
+                self.last.insert(address.clone(), now);
+                true
+            }
+        }
+    }
+}
+
+/*
+ * Make sure there is a current signed pre key, and prune the ones past their grace period
+ *
+ * Returns the id of the current signed pre key, and whether a new one was generated.
+ */
+async fn rotate_signed_pre_key(storage: &mut SyncableStore, policy: RotationPolicy, now: u64) -> Result<(u32, bool), Error> {
+    let mut csprng = OsRng;
+
+    let current = storage.signed_pre_key_store.current()?;
+    let rotated = match current {
+        Some((signed_pre_key_id, timestamp)) if now.saturating_sub(timestamp) < policy.max_age => (signed_pre_key_id, false),
+        _ => {
+            // Ids are 24 bits on the wire
+            let signed_pre_key_id = current.map(|(id, _)| id % 0xFFFFFF + 1).unwrap_or(1);
+            let signed_pre_key_pair = KeyPair::generate(&mut csprng);
+
+            let signed_pre_key_public = signed_pre_key_pair.public_key.serialize();
+            let signed_pre_key_signature = storage
+                .get_identity_key_pair(None).await?
+                .private_key()
+                .calculate_signature(&signed_pre_key_public, &mut csprng)?;
+
+            storage.save_signed_pre_key(
+                signed_pre_key_id,
+                &SignedPreKeyRecord::new(
+                    signed_pre_key_id,
+                    now,
+                    &signed_pre_key_pair,
+                    &signed_pre_key_signature,
+                ),
+                None
+            ).await?;
+
+            (signed_pre_key_id, true)
+        }
+    };
+
+    storage.signed_pre_key_store.prune(now, policy.grace_period)?;
+
+    Ok(rotated)
+}
+
+/*
+ * Number of unused one-time bundles to keep available on the server by default
+ */
+const PRE_KEY_TARGET: u32 = 10;
+
+fn now() -> u64 {
+    Date::now() as u64
+}
+
+/*
+ * Serialize a bundle for the given signed pre key, the one-time pre key is optional
+ */
+async fn encode_bundle(storage: &SyncableStore, pre_key: Option<(PreKeyId, PublicKey)>, signed_pre_key_id: u32) -> Result<String, Error> {
+    let signed_pre_key_record = storage.get_signed_pre_key(signed_pre_key_id, None).await?;
+    let identity_key = *storage.get_identity_key_pair(None).await?.identity_key();
+
+    let pre_key_bundle = PreKeyBundleSerde::try_from(PreKeyBundle::new(
+        storage.get_local_registration_id(None).await?,
+        storage.device_id,
+        pre_key,
+        signed_pre_key_id,
+        signed_pre_key_record.public_key()?,
+        signed_pre_key_record.signature()?,
+        identity_key,
+    )?)?;
+
+    Ok(base64::encode(pre_key_bundle.serialize()?))
+}
+
+/*
+ * The last resort bundle only holds the signed pre key. The server hands it out once all
+ * one-time bundles are used up and never deletes it, so it is replaced on every rotation.
+ */
+async fn publish_last_resort_bundle(storage: &SyncableStore, signed_pre_key_id: u32) -> Result<(), Error> {
+    let bundle = encode_bundle(storage, None, signed_pre_key_id).await?;
+    let payload = format!("{{\"device_id\": {}, \"bundle\": \"{}\" }}", storage.device_id, bundle);
+
+    storage.transport.request("PUT", "/protocol/bundles/last-resort", Some(payload)).await?;
+
+    Ok(())
+}
+
+/*
+ * Rotate the signed pre key when it is due, see `rotate_signed_pre_key`
+ *
+ * One-time bundles embed the signed pre key they were generated with, and become unusable
+ * once that key is pruned. So on rotation the outstanding bundles are retired, and the last
+ * resort bundle is replaced. The caller then publishes fresh bundles for the new key. When
+ * any of this fails the new key is dropped again, so that the next attempt starts over.
+ *
+ * Returns the id of the current signed pre key, and whether a new one was generated.
+ */
+async fn ensure_signed_pre_key(storage: &mut SyncableStore, policy: RotationPolicy, now: u64) -> Result<(u32, bool), Error> {
+    let (signed_pre_key_id, rotated) = rotate_signed_pre_key(storage, policy, now).await?;
+
+    if rotated {
+        if let Err(e) = retire_pre_key_bundles(storage, signed_pre_key_id).await {
+            storage.signed_pre_key_store.remove(signed_pre_key_id);
+            return Err(e);
+        }
+    }
+
+    Ok((signed_pre_key_id, rotated))
+}
+
+/*
+ * Delete all unclaimed one-time bundles from the server, along with their pre keys, and
+ * publish the last resort bundle for the new signed pre key
+ *
+ * The server responds with the ids of the bundles it deleted.
+ */
+async fn retire_pre_key_bundles(storage: &mut SyncableStore, signed_pre_key_id: u32) -> Result<(), Error> {
+    let response = storage.transport.request("DELETE", &format!("/protocol/bundles?device_id={}", storage.device_id), None).await?;
+    let retired: Vec<PreKeyId> = match response.as_array() {
+        Some(ids) => ids.iter()
+            .map(|id| id.as_u64().map(|id| id as PreKeyId))
+            .collect::<Option<_>>()
+            .ok_or_else(|| Error::Malformed("Invalid bundle ids".to_string()))?,
+        None if response.is_null() => vec![],
+        None => return Err(Error::Malformed("Invalid bundle ids".to_string()))
+    };
+
+    for pre_key_id in retired {
+        storage.remove_pre_key(pre_key_id, None).await?;
+    }
+
+    publish_last_resort_bundle(storage, signed_pre_key_id).await
+}
+
+/*
+ * Publish `count` new one-time bundles for the given signed pre key
+ */
+async fn gen_pre_key_bundles(storage: &mut SyncableStore, signed_pre_key_id: u32, count: u32) -> Result<(), Error> {
+    let mut csprng = OsRng;
+
+    let response = storage.transport.request("GET", &format!("/protocol/bundles?device_id={}", storage.device_id), None).await?;
+    let bundle_id = match response.as_u64() {
+        Some(i) => (i as u32) + 1,
+        None => 1
+    };
+
+    for i in bundle_id..bundle_id+count {
+        let pre_key_id = i;
+        let pre_key_pair = KeyPair::generate(&mut csprng);
+
+        storage.save_pre_key(pre_key_id, &PreKeyRecord::new(pre_key_id, &pre_key_pair), None).await?;
+
+        let bundle = encode_bundle(storage, Some((pre_key_id, pre_key_pair.public_key)), signed_pre_key_id).await?;
+        let payload = format!("{{\"device_id\": {}, \"bundle_id\": {}, \"bundle\": \"{}\" }}", storage.device_id, pre_key_id, bundle);
+
+        storage.transport.request("POST", "/protocol/bundles", Some(payload)).await?;
+    };
+
+    Ok(())
+}
+
+/*
+ * Top up the unused one-time bundles on the server to `target`
+ *
+ * The signed pre key is rotated first when it is due, which retires all outstanding bundles.
+ * Returns the number of bundles that were published.
+ */
+async fn replenish_pre_key_bundles(storage: &mut SyncableStore, policy: RotationPolicy, target: u32, now: u64) -> Result<u32, Error> {
+    let (signed_pre_key_id, rotated) = ensure_signed_pre_key(storage, policy, now).await?;
+
+    let count = match rotated {
+        true => 0,
+        false => {
+            let response = storage.transport.request("GET", &format!("/protocol/bundles/count?device_id={}", storage.device_id), None).await?;
+            response.as_u64().ok_or_else(|| Error::Malformed("Invalid bundle count".to_string()))? as u32
+        }
+    };
+
+    if count >= target {
+        return Ok(0);
+    }
+
+    gen_pre_key_bundles(storage, signed_pre_key_id, target - count).await?;
+
+    Ok(target - count)
+}
+
+/*
+ * Register a new device for the current user, the server hands out the device id
+ */
+async fn create_device(transport: &Rc<dyn Transport>) -> Result<u32, Error> {
+    let json = transport.request("POST", "/protocol/devices", None).await?;
+
+    json["device_id"].as_u64().map(|d| d as u32).ok_or_else(|| Error::Malformed("Invalid device id".to_string()))
+}
+
+/*
+ * Get all the devices of a user. When no user id is given, the devices of the current
+ * user are returned instead.
+ */
+async fn get_device_ids(transport: &Rc<dyn Transport>, user_id: Option<&String>) -> Result<Vec<u32>, Error> {
+    let path = match user_id {
+        Some(user_id) => format!("/protocol/devices/{}", user_id),
+        None => "/protocol/devices".to_string()
+    };
+    let json = transport.request("GET", &path, None).await?;
+
+    json.as_array()
+        .ok_or_else(|| Error::Malformed("Invalid device list".to_string()))?
+        .iter()
+        .map(|d| d.as_u64().and_then(|d| u32::try_from(d).ok()).ok_or_else(|| Error::Malformed("Invalid device id".to_string())))
+        .collect()
+}
+
+/*
+ * Fingerprint of our identity key and theirs, none when we do not know their identity yet
+ */
+async fn get_fingerprint(storage: &SyncableStore, our_id: &String, their_id: &String) -> Result<Option<Fingerprint>, Error> {
+    let our_identity_key = *storage.identity_store.get_identity_key_pair(None).await?.identity_key();
+
+    // All devices of a user share the same identity key, which is the one verification is tracked for
+    match storage.identity_store.user_identity(their_id) {
+        Some(their_identity_key) => Ok(Some(Fingerprint::new(2, 5200, our_id.as_bytes(), &our_identity_key, their_id.as_bytes(), &their_identity_key)?)),
+        None => Ok(None)
+    }
+}
+
+/*
+ * Compare a fingerprint scanned from their device with ours, marks them verified on a match
+ */
+async fn compare_scannable_fingerprint(storage: &mut SyncableStore, our_id: &String, their_id: &String, scanned: &[u8]) -> Result<bool, Error> {
+    let fingerprint = get_fingerprint(storage, our_id, their_id).await?.ok_or_else(|| Error::IdentityNotFound(their_id.clone()))?;
+
+    if !fingerprint.scannable.compare(scanned)? {
+        return Ok(false);
+    }
+    storage.identity_store.mark_verified(their_id)?;
+
+    Ok(true)
+}
+
+/*
+ * Claim a one-time bundle of the device, or fall back to its last resort bundle
+ */
+async fn fetch_pre_key_bundle(storage: &SyncableStore, address: &ProtocolAddress) -> Result<PreKeyBundle, Error> {
+    let (user_id, device_id) = (address.name(), address.device_id());
+    let response = storage.transport.request("GET", &format!("/protocol/bundles/{}/{}", user_id, device_id), None).await?;
+    let mut bundle = None;
+
+    if let Some(bundle_id) = response.as_u64() {
+        let response = storage.transport.request("DELETE", &format!("/protocol/bundles/{}/{}/{}", user_id, device_id, bundle_id), None).await?;
+        bundle = response.as_str().map(|b| b.to_string());
+    }
+
+    // All one-time bundles are used up, fall back to the last resort bundle
+    if bundle.is_none() {
+        let response = storage.transport.request("GET", &format!("/protocol/bundles/{}/{}/last-resort", user_id, device_id), None).await?;
+        bundle = response.as_str().map(|b| b.to_string());
+    }
+
+    let bundle = bundle.ok_or_else(|| Error::NoPreKeyBundle(address.to_string()))?;
+    let bundle = base64::decode(bundle).map_err(|_| Error::Malformed("Invalid pre key bundle".to_string()))?;
+
+    PreKeyBundle::try_from(PreKeyBundleSerde::deserialize(&bundle[..])?)
+}
+
+/*
+ * Encrypt a message for every device of the given user, creating sessions where needed
+ *
+ * A user without any devices cannot be sent anything, which fails with NoPreKeyBundle rather
+ * than returning no ciphertexts at all. With a sender certificate the messages are sealed, so
+ * that the server cannot see who sent them. Returns objects of device_id: envelope and
+ * device_id: error, see `encrypt_for_device_ids`.
+ */
+async fn encrypt_for_devices(storage: &mut SyncableStore, user_id: &String, content: &Content, sender_certificate: Option<&SenderCertificate>) -> Result<(js_sys::Object, js_sys::Object), Error> {
+    let device_ids = get_device_ids(&storage.transport, Some(user_id)).await?;
+    if device_ids.is_empty() {
+        return Err(Error::NoPreKeyBundle(user_id.clone()));
+    }
+
+    encrypt_for_device_ids(storage, user_id, &device_ids, content, sender_certificate).await
+}
+
+/*
+ * Same as `encrypt_for_devices`, for the other devices of our own user. Having no other
+ * devices is fine, that just returns no ciphertexts.
+ */
+async fn encrypt_for_own_devices(storage: &mut SyncableStore, our_id: &String, content: &Content, sender_certificate: Option<&SenderCertificate>) -> Result<(js_sys::Object, js_sys::Object), Error> {
+    let device_ids: Vec<u32> = get_device_ids(&storage.transport, None).await?
+        .into_iter()
+        .filter(|device_id| *device_id != storage.device_id)
+        .collect();
+
+    encrypt_for_device_ids(storage, our_id, &device_ids, content, sender_certificate).await
+}
+
+/*
+ * A failure for one device (e.g. no bundle left) does not stop the others, it is reported
+ * next to the ciphertexts. Only when every device failed, the first error is returned instead.
+ */
+async fn encrypt_for_device_ids(storage: &mut SyncableStore, user_id: &String, device_ids: &[u32], content: &Content, sender_certificate: Option<&SenderCertificate>) -> Result<(js_sys::Object, js_sys::Object), Error> {
+    let ciphertexts = js_sys::Object::new();
+    let mut errors = Vec::new();
+    let message = Padding::Block.pad(&Message::new(content.clone(), now()).serialize()?);
+
+    for &device_id in device_ids {
+        let address = ProtocolAddress::new(user_id.clone(), device_id);
+
+        match encrypt_for_device(storage, &address, &message, sender_certificate).await {
+            Ok(envelope) => {
+                js_sys::Reflect::set(&ciphertexts, &device_id.into(), &envelope.into()).unwrap();
+            },
+            Err(e) => errors.push((device_id, e))
+        }
+    }
+
+    if errors.len() == device_ids.len() && !errors.is_empty() {
+        return Err(errors.remove(0).1);
+    }
+
+    let device_errors = js_sys::Object::new();
+    for (device_id, e) in errors {
+        js_sys::Reflect::set(&device_errors, &device_id.into(), &e.into()).unwrap();
+    }
+
+    Ok((ciphertexts, device_errors))
+}
+
+async fn encrypt_for_device(storage: &mut SyncableStore, address: &ProtocolAddress, message: &[u8], sender_certificate: Option<&SenderCertificate>) -> Result<String, Error> {
+    let mut csprng = OsRng;
+
+    // No active session means we need to fetch a pre_key_bundle
+    let has_session = storage.session_store.load_session(address, None).await?.map(|record| record.has_current_session_state()).unwrap_or(false);
+    if !has_session {
+        let pre_key_bundle = fetch_pre_key_bundle(storage, address).await?;
+
+        // Create the session
+        process_prekey_bundle(
+            address,
+            &mut storage.session_store,
+            &mut storage.identity_store,
+            &pre_key_bundle,
+            &mut csprng,
+            None,
+        ).await?;
+        storage.session_store.set_established(address, now());
+    }
+
+    match sender_certificate {
+        Some(sender_certificate) => {
+            let sealed = sealed_sender_encrypt(address, sender_certificate, message, &mut storage.session_store, &mut storage.identity_store, None, &mut csprng).await?;
+
+            // The sender device is part of the sealed content
+            Envelope::new(MessageType::Sealed, 0, Padding::Block, &sealed).encode()
+        },
+        None => {
+            let encrypted = message_encrypt(message, address, &mut storage.session_store, &mut storage.identity_store, None).await?;
+            seal(storage.device_id, Padding::Block, encrypted)
+        }
+    }
+}
+
+/*
+ * Wrap an encrypted message in an envelope, ready to be sent
+ */
+fn seal(device_id: u32, padding: Padding, encrypted: CiphertextMessage) -> Result<String, Error> {
+    let message_type = match encrypted {
+        CiphertextMessage::PreKeySignalMessage(_) => MessageType::PreKey,
+        CiphertextMessage::SignalMessage(_) => MessageType::Signal,
+        _ => return Err(Error::Protocol("Unexpected message type".to_string()))
+    };
+
+    Envelope::new(message_type, device_id, padding, encrypted.serialize()).encode()
+}
+
+/*
+ * Messages from before the envelope, which may be either kind of Signal message
+ */
+fn parse_legacy_message(session_exists: bool, address: &ProtocolAddress, message: &String) -> Result<CiphertextMessage, Error> {
+    let bytes = base64::decode(message).map_err(|_| Error::Malformed("Message is not valid base64".to_string()))?;
+
+    if session_exists {
+        // Prekey messages may be queued up, maybe fallback to prekey type
+        return match SignalMessage::try_from(&bytes[..]) {
+            Ok(message) => Ok(CiphertextMessage::SignalMessage(message)),
+            Err(_) => Ok(CiphertextMessage::PreKeySignalMessage(PreKeySignalMessage::try_from(&bytes[..]).map_err(decrypt_error)?))
+        };
+    }
+
+    match PreKeySignalMessage::try_from(&bytes[..]) {
+        Ok(message) => Ok(CiphertextMessage::PreKeySignalMessage(message)),
+        // A regular message without a session, i.e. our state was restored from an older sync
+        Err(e) => match SignalMessage::try_from(&bytes[..]) {
+            Ok(_) => Err(Error::SessionBroken(format!("No session with {}", address))),
+            Err(_) => Err(decrypt_error(e))
+        }
+    }
+}
+
+/*
+ * Returns the message, and whether one of our one-time pre keys was used up
+ */
+async fn decrypt_message(storage: &mut SyncableStore, address: &ProtocolAddress, message: &String) -> Result<(Message, bool), Error> {
+    let session_exists = storage.session_store.load_session(address, None).await?.is_some();
+
+    let (ctext, envelope) = if message::is_envelope(message) {
+        let envelope = Envelope::decode(message)?;
+        if envelope.message_type == MessageType::Sealed {
+            return Err(Error::Malformed("Sealed sender messages have to be decrypted with decrypt_sealed".to_string()));
+        }
+        if envelope.message_type == MessageType::SenderKey {
+            return Err(Error::Malformed("Sender key messages have to be decrypted with group_decrypt".to_string()));
+        }
+        if envelope.sender_device != address.device_id() {
+            return Err(Error::Malformed(format!("Message was not sent by {}", address)));
+        }
+
+        let bytes = envelope.ciphertext()?;
+        let ctext = match envelope.message_type {
+            MessageType::PreKey => CiphertextMessage::PreKeySignalMessage(PreKeySignalMessage::try_from(&bytes[..]).map_err(decrypt_error)?),
+            MessageType::Signal if !session_exists => return Err(Error::SessionBroken(format!("No session with {}", address))),
+            MessageType::Signal => CiphertextMessage::SignalMessage(SignalMessage::try_from(&bytes[..]).map_err(decrypt_error)?),
+            MessageType::Sealed | MessageType::SenderKey => unreachable!()
+        };
+
+        (ctext, Some(envelope))
+    } else {
+        (parse_legacy_message(session_exists, address, message)?, None)
+    };
+
+    let (plaintext, consumed) = decrypt_ciphertext(storage, address, &ctext).await?;
+
+    Ok((open_content(envelope, plaintext)?, consumed))
+}
+
+/*
+ * Open a sealed sender envelope
+ *
+ * Returns the sender, which is authenticated by their certificate, and the message inside.
+ * The certificate is checked against the time the message was sent.
+ */
+async fn unseal_message(storage: &mut SyncableStore, trust_root: &PublicKey, message: &String, now: u64) -> Result<(ProtocolAddress, CiphertextMessage, Envelope), Error> {
+    let envelope = Envelope::decode(message)?;
+    if envelope.message_type != MessageType::Sealed {
+        return Err(Error::Malformed("Not a sealed sender message".to_string()));
+    }
+
+    let usmc = sealed_sender_decrypt_to_usmc(&envelope.ciphertext()?, &mut storage.identity_store, None).await.map_err(decrypt_error)?;
+    let sender = usmc.sender()?;
+
+    validate_sender(storage, sender, trust_root, now).await?;
+
+    let address = ProtocolAddress::new(sender.sender_uuid()?.to_string(), sender.sender_device_id()?);
+    let ctext = match usmc.msg_type()? {
+        CiphertextMessageType::PreKey => CiphertextMessage::PreKeySignalMessage(PreKeySignalMessage::try_from(usmc.contents()?).map_err(decrypt_error)?),
+        CiphertextMessageType::Whisper => CiphertextMessage::SignalMessage(SignalMessage::try_from(usmc.contents()?).map_err(decrypt_error)?),
+        _ => return Err(Error::Malformed("Unexpected sealed message type".to_string()))
+    };
+
+    Ok((address, ctext, envelope))
+}
+
+/*
+ * Check the sender certificate of an incoming sealed message
+ *
+ * Expiry is checked against our own clock, rather than a timestamp picked by the sender.
+ * Like libsignal's `sealed_sender_decrypt`, messages that claim to come from this very device
+ * are refused.
+ */
+async fn validate_sender(storage: &SyncableStore, sender: &SenderCertificate, trust_root: &PublicKey, now: u64) -> Result<(), Error> {
+    if !sender.validate(trust_root, now)? {
+        return Err(Error::InvalidSignature);
+    }
+
+    let identity_key = *storage.identity_store.get_identity_key_pair(None).await?.identity_key();
+    if sender.key()? == *identity_key.public_key() && sender.sender_device_id()? == storage.device_id {
+        return Err(Error::Protocol("Sealed message was sent by this device".to_string()));
+    }
+
+    Ok(())
+}
+
+/*
+ * Returns the plaintext, and whether one of our one-time pre keys was used up
+ */
+async fn decrypt_ciphertext(storage: &mut SyncableStore, address: &ProtocolAddress, ctext: &CiphertextMessage) -> Result<(Vec<u8>, bool), Error> {
+    let mut csprng = OsRng;
+    let has_session = storage.session_store.load_session(address, None).await?.map(|record| record.has_current_session_state()).unwrap_or(false);
+
+    let plaintext = message_decrypt(
+        ctext,
+        address,
+        &mut storage.session_store,
+        &mut storage.identity_store,
+        &mut storage.pre_key_store,
+        &mut storage.signed_pre_key_store,
+        &mut csprng,
+        None,
+    ).await.map_err(decrypt_error)?;
+
+    // libsignal already removed the one-time pre key the message was built on
+    let consumed = match ctext {
+        CiphertextMessage::PreKeySignalMessage(message) => message.pre_key_id().is_some(),
+        _ => false
+    };
+
+    if !has_session && matches!(ctext, CiphertextMessage::PreKeySignalMessage(_)) {
+        storage.session_store.set_established(address, now());
+    }
+
+    Ok((plaintext, consumed))
+}
+
+/*
+ * Before the envelope, the plaintext was just the body. Version 1 envelopes hold the content
+ * without a timestamp.
+ */
+fn open_content(envelope: Option<Envelope>, plaintext: Vec<u8>) -> Result<Message, Error> {
+    match envelope {
+        Some(envelope) if envelope.version == 1 => Ok(Message {
+            content: Content::deserialize(&envelope.padding.unpad(plaintext)?)?,
+            timestamp: None
+        }),
+        Some(envelope) => Message::deserialize(&envelope.padding.unpad(plaintext)?),
+        None => Ok(Message { content: Content::new(message::BINARY, plaintext), timestamp: None })
+    }
+}
+
+/*
+ * Encrypt a message once for the whole group, padded and in an envelope like pairwise messages
+ */
+async fn encrypt_group_message(storage: &mut SyncableStore, sender: &ProtocolAddress, distribution_id: Uuid, content: &Content, now: u64) -> Result<String, Error> {
+    let mut csprng = OsRng;
+    let message = Padding::Block.pad(&Message::new(content.clone(), now).serialize()?);
+
+    let encrypted = group_encrypt(&mut storage.sender_key_store, sender, distribution_id, &message, &mut csprng, None).await?;
+
+    Envelope::new(MessageType::SenderKey, sender.device_id(), Padding::Block, encrypted.serialized()).encode()
+}
+
+/*
+ * Group messages from before the envelope are bare base64, and hold just the text
+ */
+async fn decrypt_group_message(storage: &mut SyncableStore, address: &ProtocolAddress, message: &String) -> Result<Message, Error> {
+    let (bytes, envelope) = if message::is_envelope(message) {
+        let envelope = Envelope::decode(message)?;
+        if envelope.message_type != MessageType::SenderKey {
+            return Err(Error::Malformed("Not a sender key message".to_string()));
+        }
+        if envelope.sender_device != address.device_id() {
+            return Err(Error::Malformed(format!("Message was not sent by {}", address)));
+        }
+
+        (envelope.ciphertext()?, Some(envelope))
+    } else {
+        (base64::decode(message).map_err(|_| Error::Malformed("Message is not valid base64".to_string()))?, None)
+    };
+
+    let plaintext = group_decrypt(&bytes[..], &mut storage.sender_key_store, address, None).await.map_err(decrypt_error)?;
+
+    open_content(envelope, plaintext)
+}
+
+async fn fetch_sender_certificate(storage: &SyncableStore) -> Result<SenderCertificate, Error> {
+    let response = storage.transport.request("GET", "/protocol/certificate", None).await?;
+    let certificate = response.as_str().ok_or_else(|| Error::Malformed("Invalid sender certificate".to_string()))?;
+    let certificate = base64::decode(certificate).map_err(|_| Error::Malformed("Sender certificate is not valid base64".to_string()))?;
+
+    Ok(SenderCertificate::deserialize(&certificate)?)
+}
+
+/*
+ * Check that the sender certificate is ours, and was issued under the trust root
+ */
+async fn validate_sender_certificate(storage: &SyncableStore, certificate: &SenderCertificate, trust_root: &PublicKey, now: u64) -> Result<(), Error> {
+    if !certificate.validate(trust_root, now)? {
+        return Err(Error::InvalidSignature);
+    }
+
+    let identity_key = *storage.identity_store.get_identity_key_pair(None).await?.identity_key();
+    if certificate.key()? != *identity_key.public_key() || certificate.sender_device_id()? != storage.device_id {
+        return Err(Error::Malformed("Sender certificate was not issued to us".to_string()));
+    }
+
+    Ok(())
+}
+
+/*
+ * Replace the broken session with the device by a new one, set up from a fresh bundle
+ *
+ * The broken session is archived rather than removed, and returns the control message that
+ * asks the device to resend `message_id`. Decrypting it sets up the new session on their end.
+ */
+async fn reset_session(storage: &mut SyncableStore, address: &ProtocolAddress, message_id: &str, now: u64) -> Result<String, Error> {
+    let mut csprng = OsRng;
+    let pre_key_bundle = fetch_pre_key_bundle(storage, address).await?;
+
+    // Promotes the new session, and archives the current one
+    process_prekey_bundle(address, &mut storage.session_store, &mut storage.identity_store, &pre_key_bundle, &mut csprng, None).await?;
+    storage.session_store.set_established(address, now);
+
+    let content = Content::new(message::SESSION_RESET, json!({ "message_id": message_id }).to_string().into_bytes());
+    let encrypted = message_encrypt(&Padding::Block.pad(&Message::new(content, now).serialize()?), address, &mut storage.session_store, &mut storage.identity_store, None).await?;
+
+    seal(storage.device_id, Padding::Block, encrypted)
+}
+
+/*
+ * The id of the message to resend, from a decrypted session reset
+ */
+fn parse_session_reset(content: &Content) -> Result<String, Error> {
+    if content.content_type != message::SESSION_RESET {
+        return Err(Error::Malformed("Not a session reset".to_string()));
+    }
+
+    let payload: serde_json::Value = serde_json::from_slice(&content.body).map_err(|_| Error::Malformed("Invalid session reset".to_string()))?;
+
+    match payload["message_id"].as_str() {
+        Some(message_id) => Ok(message_id.to_string()),
+        None => Err(Error::Malformed("Invalid session reset".to_string()))
+    }
+}
+
+/*
+ * Duplicates and untrusted identities are kept as is, anything else just failed to decrypt
+ */
+fn decrypt_error(e: SignalProtocolError) -> Error {
+    match e {
+        SignalProtocolError::DuplicatedMessage(_, _) | SignalProtocolError::UntrustedIdentity(_) => e.into(),
+        // The session (or the pre key it was set up with) is gone or out of step, only a new one helps
+        SignalProtocolError::SessionNotFound(..)
+        | SignalProtocolError::InvalidMessage(..)
+        | SignalProtocolError::InvalidSessionStructure(..)
+        | SignalProtocolError::InvalidPreKeyId
+        | SignalProtocolError::InvalidSignedPreKeyId => {
+            console::log_2(&"Session is broken: ".into(), &e.to_string().into());
+            Error::SessionBroken(e.to_string())
+        },
+        e => {
+            console::log_2(&"Error when decrypting message: ".into(), &e.to_string().into());
+            Error::DecryptFailed(e.to_string())
+        }
+    }
+}
+
+fn to_utf8(decrypted: Vec<u8>) -> Result<String, Error> {
+    String::from_utf8(decrypted).map_err(|_| Error::Malformed("Message is not valid utf-8".to_string()))
+}
+
+#[wasm_bindgen]
+impl Protocol {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Protocol {
+        console_error_panic_hook::set_once();
+
+        Protocol {
+            inner: Arc::new(ProtocolInner {
+                storage: Lock::new(None),
+                transport: RefCell::new(None),
+                rotation: RefCell::new(RotationPolicy::default()),
+                pre_key_target: RefCell::new(PRE_KEY_TARGET),
+                timeout: RefCell::new(None),
+                message_ids: RefCell::new(Vec::new()),
+                resets: RefCell::new(SessionResets::default()),
+                reset_callback: RefCell::new(None),
+                trust_root: RefCell::new(None),
+                sender_certificate: RefCell::new(None),
+                sealed_sender: RefCell::new(false),
+                outbox_callback: RefCell::new(None),
+                outbox_timeout: RefCell::new(None),
+                device_id: RefCell::new(None)
+            })
+        }
+    }
+
+    pub fn init(&self, secret_key: String, device_id: u32, api_basepath: JsValue) -> Promise {
+        let _self = self.inner.clone();
+        let transport = self.inner.transport(&api_basepath);
+
+        let done = async move {
+            match SyncableStore::new(secret_key, transport, device_id).await {
+                Ok(storage) => {
+                    let next_attempt = storage.outbox.next_attempt();
+                    _self.install_storage(storage).await;
+
+                    // Pick up where the outbox was left
+                    _self.schedule_outbox(next_attempt);
+
+                    Ok(JsValue::undefined())
+                },
+                Err(e) => Err(e.into())
+            }
+        };
+
+        wasm_bindgen_futures::future_to_promise(done)
+    }
+
+    /*
+     * Same as `init`, for a device whose synced state is still in the legacy format
+     *
+     * `init` rejects such a state with MigrationRequired. This loads it once and syncs it back
+     * in the current format, after which `init` works as usual.
+     */
+    pub fn migrate_state(&self, secret_key: String, device_id: u32, api_basepath: JsValue) -> Promise {
+        let _self = self.inner.clone();
+        let transport = self.inner.transport(&api_basepath);
+
+        let done = async move {
+            match SyncableStore::migrate(secret_key, transport, device_id).await {
+                Ok(storage) => {
+                    let next_attempt = storage.outbox.next_attempt();
+                    _self.install_storage(storage).await;
+
+                    _self.schedule_outbox(next_attempt);
+
+                    Ok(JsValue::undefined())
+                },
+                Err(e) => Err(e.into())
+            }
+        };
+
+        wasm_bindgen_futures::future_to_promise(done)
+    }
+
+    pub fn register(&self, secret_key: String, api_basepath: JsValue) -> Promise {
+        let _self = self.inner.clone();
+        let transport = self.inner.transport(&api_basepath);
+
+        let done = async move {
+            let registered: Result<(SyncableStore, String), Error> = async {
+                let device_id = create_device(&transport).await?;
+                let mut storage = SyncableStore::register(secret_key, transport, device_id, None)?;
+
+                // Generate and publish some bundles
+                let (signed_pre_key_id, _) = ensure_signed_pre_key(&mut storage, _self.rotation_policy(), now()).await?;
+                gen_pre_key_bundles(&mut storage, signed_pre_key_id, _self.pre_key_target()).await?;
+
+                // Share our identity public key
+                let identity_key = base64::encode(storage.identity_store.get_identity_key_pair(None).await?.public_key().serialize());
+
+                // Save state
+                storage.sync(None).await?;
+
+                Ok((storage, identity_key))
+            }.await;
+
+            match registered {
+                Ok((storage, identity_key)) => {
+                    let obj = js_sys::Object::new();
+                    js_sys::Reflect::set(&obj, &"identity_key".into(), &identity_key.into()).unwrap();
+                    js_sys::Reflect::set(&obj, &"device_id".into(), &storage.device_id.into()).unwrap();
+
+                    _self.install_storage(storage).await;
+
+                    Ok(obj.into())
+                },
+                Err(e) => Err(e.into())
+            }
+        };
+
+        wasm_bindgen_futures::future_to_promise(done)
+    }
+
+    /*
+     * Register an additional device for an existing user
+     *
+     * The identity key is copied over from one of the existing devices, everything else
+     * (registration id, bundles, sessions) is unique to this device. Returns the new device id.
+     */
+    pub fn link_device(&self, secret_key: String, api_basepath: JsValue) -> Promise {
+        let _self = self.inner.clone();
+        let transport = self.inner.transport(&api_basepath);
+
+        let done = async move {
+            let linked: Result<SyncableStore, Error> = async {
+                let existing_device_id = *get_device_ids(&transport, None).await?.first().ok_or_else(|| Error::Protocol("Cannot link device: no existing device found".to_string()))?;
+                let existing = SyncableStore::new(secret_key.clone(), transport.clone(), existing_device_id).await?;
+                let identity_key = existing.identity_store.get_identity_key_pair(None).await?;
+
+                let device_id = create_device(&transport).await?;
+                let mut storage = SyncableStore::register(secret_key, transport, device_id, Some(identity_key))?;
+
+                let (signed_pre_key_id, _) = ensure_signed_pre_key(&mut storage, _self.rotation_policy(), now()).await?;
+                gen_pre_key_bundles(&mut storage, signed_pre_key_id, _self.pre_key_target()).await?;
+                storage.sync(None).await?;
+
+                Ok(storage)
+            }.await;
+
+            match linked {
+                Ok(storage) => {
+                    let device_id = storage.device_id;
+                    _self.install_storage(storage).await;
+
+                    Ok(JsValue::from(device_id))
+                },
+                Err(e) => Err(e.into())
+            }
+        };
+
+        wasm_bindgen_futures::future_to_promise(done)
+    }
+
+    pub fn get_devices(&self, user_id: String) -> Promise {
+        let _self = self.inner.clone();
+
+        wasm_bindgen_futures::future_to_promise(async move {
+            match _self.clone_storage().await {
+                Ok(storage) => {
+                    match get_device_ids(&storage.transport, Some(&user_id)).await {
+                        Ok(device_ids) => Ok(serde_wasm_bindgen::to_value(&device_ids)?),
+                        Err(e) => Err(e.into())
+                    }
+                },
+                Err(e) => Err(e.into())
+            }
+        })
+    }
+
+    pub fn get_device_id(&self) -> Option<u32> {
+        *self.inner.device_id.borrow()
+    }
+
+    pub fn add_pre_key_bundles(&self) -> Promise {
+        let _self = self.inner.clone();
+
+        wasm_bindgen_futures::future_to_promise(async move {
+            match _self.take_storage().await {
+                Ok(mut storage) => {
+                    let maybe_generated = async {
+                        let (signed_pre_key_id, rotated) = ensure_signed_pre_key(&mut storage, _self.rotation_policy(), now()).await?;
+
+                        // A rotation retired the outstanding bundles, so start over from the target
+                        let count = match rotated {
+                            true => _self.pre_key_target().max(5),
+                            false => 5
+                        };
+
+                        gen_pre_key_bundles(&mut storage, signed_pre_key_id, count).await?;
+                        storage.sync(None).await
+                    }.await;
+
+                    match maybe_generated {
+                        Ok(_) => Ok(JsValue::undefined()),
+                        Err(e) => Err(e.into())
+                    }
+                },
+                Err(e) => Err(e.into())
+            }
+        })
+    }
+
+    /*
+     * Configure when the signed pre key is rotated, both values are in ms
+     *
+     * Throws Malformed when max_age is not positive, or grace_period is negative.
+     */
+    pub fn set_rotation_policy(&self, max_age: f64, grace_period: f64) -> Result<(), JsValue> {
+        self.inner.rotation.replace(RotationPolicy::from_js(max_age, grace_period)?);
+
+        Ok(())
+    }
+
+    /*
+     * Number of unused one-time bundles to keep available on the server
+     */
+    pub fn set_pre_key_target(&self, target: u32) {
+        self.inner.pre_key_target.replace(target);
+    }
+
+    /*
+     * Top up the one-time bundles on the server, resolves to the number that was published
+     */
+    pub fn replenish_pre_key_bundles(&self) -> Promise {
+        let _self = self.inner.clone();
+
+        wasm_bindgen_futures::future_to_promise(async move {
+            match _self.take_storage().await {
+                Ok(mut storage) => {
+                    let maybe_replenished = match replenish_pre_key_bundles(&mut storage, _self.rotation_policy(), _self.pre_key_target(), now()).await {
+                        Ok(0) => Ok(0),
+                        Ok(published) => storage.sync(None).await.map(|_| published),
+                        Err(e) => Err(e)
+                    };
+
+                    match maybe_replenished {
+                        Ok(published) => Ok(published.into()),
+                        Err(e) => Err(e.into())
+                    }
+                },
+                Err(e) => Err(e.into())
+            }
+        })
+    }
+
+    /*
+     * Rotate the signed pre key when it is due, and replace the bundles with ones for the new key
+     *
+     * Should be called periodically, resolves to whether the key was rotated.
+     */
+    pub fn rotate_signed_pre_key(&self) -> Promise {
+        let _self = self.inner.clone();
+
+        wasm_bindgen_futures::future_to_promise(async move {
+            match _self.take_storage().await {
+                Ok(mut storage) => {
+                    let policy = _self.rotation_policy();
+                    let maybe_rotated = async {
+                        let (signed_pre_key_id, rotated) = ensure_signed_pre_key(&mut storage, policy, now()).await?;
+
+                        // The old bundles were retired, replace them with a full set
+                        if rotated {
+                            gen_pre_key_bundles(&mut storage, signed_pre_key_id, _self.pre_key_target()).await?;
+                        }
+                        storage.sync(None).await?;
+
+                        Ok::<bool, Error>(rotated)
+                    }.await;
+
+                    match maybe_rotated {
+                        Ok(rotated) => Ok(rotated.into()),
+                        Err(e) => Err(e.into())
+                    }
+                },
+                Err(e) => Err(e.into())
+            }
+        })
+    }
+
+    /*
+     * Displayable fingerprint (safety number) to compare with the user, as 60 digits
+     */
+    pub fn get_fingerprint(&self, our_id: String, their_id: String) -> Promise {
+        let _self = self.inner.clone();
+
+        wasm_bindgen_futures::future_to_promise(async move {
+            match _self.clone_storage().await {
+                Ok(storage) => {
+                    match get_fingerprint(&storage, &our_id, &their_id).await {
+                        Ok(Some(fingerprint)) => Ok(JsValue::from_str(&fingerprint.display.to_string())),
+                        Ok(None) => Ok(JsValue::undefined()),
+                        Err(e) => Err(e.into())
+                    }
+                },
+                Err(e) => Err(e.into())
+            }
+        })
+    }
+
+    /*
+     * Scannable fingerprint as a Uint8Array, to be rendered as a QR code
+     */
+    pub fn get_scannable_fingerprint(&self, our_id: String, their_id: String) -> Promise {
+        let _self = self.inner.clone();
+
+        wasm_bindgen_futures::future_to_promise(async move {
+            match _self.clone_storage().await {
+                Ok(storage) => {
+                    match get_fingerprint(&storage, &our_id, &their_id).await {
+                        Ok(Some(fingerprint)) => {
+                            let scannable = fingerprint.scannable.serialize().map_err(Error::from)?;
+
+                            Ok(js_sys::Uint8Array::from(&scannable[..]).into())
+                        },
+                        Ok(None) => Ok(JsValue::undefined()),
+                        Err(e) => Err(e.into())
+                    }
+                },
+                Err(e) => Err(e.into())
+            }
+        })
+    }
+
+    /*
+     * Check a fingerprint scanned from the device of the user, resolves to whether it matched
+     *
+     * On a match their identity is marked as verified.
+     */
+    pub fn compare_scannable_fingerprint(&self, our_id: String, their_id: String, scanned: Vec<u8>) -> Promise {
+        let _self = self.inner.clone();
+        let done = async move {
+            match _self.take_storage().await {
+                Ok(mut storage) => {
+                    let maybe_matched = compare_scannable_fingerprint(&mut storage, &our_id, &their_id, &scanned).await;
+
+                    match maybe_matched {
+                        Ok(matched) => Ok(matched.into()),
+                        Err(e) => Err(e.into())
+                    }
+                },
+                Err(e) => Err(e.into())
+            }
+        };
+
+        self.schedule_sync();
+
+        wasm_bindgen_futures::future_to_promise(done)
+    }
+
+    /*
+     * Trust status of the identity key of a user, one of "unverified", "verified" or "changed"
+     *
+     * Resolves to undefined when we have not seen their identity key yet. Once a key is
+     * "changed", encrypting to and decrypting from the user fails with UntrustedIdentity until
+     * it is accepted.
+     */
+    pub fn get_identity_status(&self, user_id: String) -> Promise {
+        let _self = self.inner.clone();
+
+        wasm_bindgen_futures::future_to_promise(async move {
+            let storage = _self.take_storage().await?;
+
+            Ok(storage.identity_store.identity_status(&user_id).map(|status| JsValue::from(status.as_str())).unwrap_or(JsValue::undefined()))
+        })
+    }
+
+    /*
+     * Mark the identity key of a user as verified, i.e. after comparing fingerprints
+     */
+    pub fn mark_verified(&self, user_id: String) -> Promise {
+        let _self = self.inner.clone();
+        let done = async move {
+            _self.take_storage().await?.identity_store.mark_verified(&user_id)?;
+
+            Ok(JsValue::undefined())
+        };
+
+        self.schedule_sync();
+
+        wasm_bindgen_futures::future_to_promise(done)
+    }
+
+    /*
+     * Trust the changed identity key of a user, which drops our sessions with them
+     */
+    pub fn accept_new_identity(&self, user_id: String) -> Promise {
+        let _self = self.inner.clone();
+        let done = async move {
+            _self.take_storage().await?.accept_new_identity(&user_id)?;
+
+            Ok(JsValue::undefined())
+        };
+
+        self.schedule_sync();
+
+        wasm_bindgen_futures::future_to_promise(done)
+    }
+
+    /*
+     * All sessions, as an array of {user_id, device_id, active, established}
+     *
+     * `established` is when the session was set up in ms, if known. Sessions that are not
+     * active were archived and can only decrypt messages that were still underway.
+     */
+    pub fn list_sessions(&self) -> Promise {
+        let _self = self.inner.clone();
+
+        wasm_bindgen_futures::future_to_promise(async move {
+            let storage = _self.take_storage().await?;
+
+            let sessions: js_sys::Array = storage.session_store.list().into_iter().map(|session| {
+                let obj = js_sys::Object::new();
+                js_sys::Reflect::set(&obj, &"user_id".into(), &session.address.name().into()).unwrap();
+                js_sys::Reflect::set(&obj, &"device_id".into(), &session.address.device_id().into()).unwrap();
+                js_sys::Reflect::set(&obj, &"active".into(), &session.active.into()).unwrap();
+                js_sys::Reflect::set(&obj, &"established".into(), &session.established.map(|t| JsValue::from_f64(t as f64)).unwrap_or(JsValue::undefined())).unwrap();
+
+                JsValue::from(obj)
+            }).collect();
+
+            Ok(sessions.into())
+        })
+    }
+
+    /*
+     * Whether there is an active session with any device of the user
+     */
+    pub fn has_session(&self, user_id: String) -> Promise {
+        let _self = self.inner.clone();
+
+        wasm_bindgen_futures::future_to_promise(async move {
+            let storage = _self.take_storage().await?;
+
+            Ok(storage.session_store.has_session(&user_id).into())
+        })
+    }
+
+    /*
+     * Drop the sessions with the user altogether, resolves to how many were removed
+     *
+     * Messages that are still underway can no longer be decrypted, see `archive_session`.
+     */
+    pub fn delete_session(&self, user_id: String) -> Promise {
+        let _self = self.inner.clone();
+        let done = async move {
+            let deleted = _self.take_storage().await?.session_store.delete_sessions(&user_id);
+
+            Ok((deleted as u32).into())
+        };
+
+        self.schedule_sync();
+
+        wasm_bindgen_futures::future_to_promise(done)
+    }
+
+    /*
+     * Reset the sessions with the user, resolves to how many were archived
+     *
+     * A new session is set up with the next message, while messages that were sent in the
+     * archived session can still be decrypted.
+     */
+    pub fn archive_session(&self, user_id: String) -> Promise {
+        let _self = self.inner.clone();
+        let done = async move {
+            let archived = _self.take_storage().await?.session_store.archive_sessions(&user_id)?;
+
+            Ok((archived as u32).into())
+        };
+
+        self.schedule_sync();
+
+        wasm_bindgen_futures::future_to_promise(done)
+    }
+
+    /*
+     * Encrypt a message for every device of the given user
+     *
+     * Resolves to {ciphertexts, errors}, with ciphertexts as device_id: ciphertext and errors as
+     * device_id: Error for the devices it failed for. Rejects when it failed for every device.
+     */
+    pub fn encrypt(&self, user_id: String, message: String) -> Promise {
+        let _self = self.inner.clone();
+        let done = async move {
+            match _self.encrypt(&user_id, &Content::new(message::TEXT, message.into_bytes())).await {
+                Ok((ciphertexts, errors)) => Ok(encrypted_object(&ciphertexts, &errors).into()),
+                Err(e) => Err(e.into())
+            }
+        };
+
+        self.schedule_sync();
+
+        wasm_bindgen_futures::future_to_promise(done)
+    }
+
+    /*
+     * Encrypt a message for every device of each of the given users
+     *
+     * Resolves to {ciphertexts, errors}, with ciphertexts as user_id: {device_id: ciphertext}
+     * and errors as user_id: Error for the users it failed for. When only some devices of a user
+     * failed, their errors are given as user_id: {device_id: Error}.
+     */
+    pub fn encrypt_for_many(&self, user_ids: JsValue, message: String) -> Promise {
+        let _self = self.inner.clone();
+        let done = async move {
+            let user_ids: Vec<String> = serde_wasm_bindgen::from_value(user_ids).map_err(|_| Error::Malformed("Expected a list of user ids".to_string()))?;
+
+            match _self.encrypt_for_many(&user_ids, &Content::new(message::TEXT, message.into_bytes())).await {
+                Ok((ciphertexts, errors)) => Ok(encrypted_object(&ciphertexts, &errors).into()),
+                Err(e) => Err(e.into())
+            }
+        };
+
+        self.schedule_sync();
+
+        wasm_bindgen_futures::future_to_promise(done)
+    }
 
-    let response = request("GET".to_string(), format!("{}/protocol/bundles", storage.api_basepath), None).await;
-    let bundle_id = match response.as_f64() {
-        Some(i) => (i as u32) + 1,
-        None => 1
-    };
+    /*
+     * Same as `encrypt`, but queues the message when the backend cannot be reached
+     *
+     * Resolves to {status: "sent", ciphertexts, errors} when the message could be encrypted right away,
+     * or to {id, user_id, status: "queued"} otherwise. Queued messages are retried in the
+     * background, see `on_outbox_event`.
+     */
+    pub fn encrypt_or_queue(&self, user_id: String, message: String) -> Promise {
+        let _self = self.inner.clone();
+        let done = async move {
+            match _self.encrypt_or_queue(&user_id, &Content::new(message::TEXT, message.into_bytes())).await {
+                Ok(Outgoing::Sent(ciphertexts, errors)) => {
+                    let obj = encrypted_object(&ciphertexts, &errors);
+                    js_sys::Reflect::set(&obj, &"status".into(), &"sent".into()).unwrap();
 
-    let signed_pre_key_id = 1;
-    let signed_pre_key_record = match storage.store.get_signed_pre_key(signed_pre_key_id, None).await {
-        Ok(record) => record,
-        Err(_) => {
-            let signed_pre_key_pair = KeyPair::generate(&mut csprng);
+                    Ok(obj.into())
+                },
+                Ok(Outgoing::Queued(id)) => Ok(outbox_event(&id, &user_id, "queued", vec![]).into()),
+                Err(e) => Err(e.into())
+            }
+        };
 
-            let signed_pre_key_public = signed_pre_key_pair.public_key.serialize();
-            let signed_pre_key_signature = storage.store
-                .get_identity_key_pair(None).await.unwrap()
-                .private_key()
-                .calculate_signature(&signed_pre_key_public, &mut csprng).unwrap();
+        self.schedule_sync();
 
-            storage.store.save_signed_pre_key(
-                signed_pre_key_id,
-                &SignedPreKeyRecord::new(
-                    signed_pre_key_id,
-                    Date::now() as u64,
-                    &signed_pre_key_pair,
-                    &signed_pre_key_signature,
-                ),
-                None
-            ).await.unwrap();
+        wasm_bindgen_futures::future_to_promise(done)
+    }
 
-            storage.store.get_signed_pre_key(signed_pre_key_id, None).await.unwrap()
-        }
-    };
+    /*
+     * Follow the messages in the outbox, called as callback({id, user_id, status, ...})
+     *
+     * The status is "queued" when a message is added to the outbox, "sent" with the
+     * ciphertexts and errors once it could be encrypted, or "failed" with the error when it was given up on.
+     * Queued messages are only retried in the background once a callback is set, until then
+     * they wait for `flush_outbox`.
+     */
+    pub fn on_outbox_event(&self, callback: js_sys::Function) {
+        self.inner.outbox_callback.replace(Some(callback));
+
+        // Pick up the retries that were waiting for a callback
+        let _self = self.inner.clone();
+        let _obj = wasm_bindgen_futures::future_to_promise(async move {
+            if let Ok(storage) = _self.take_storage().await {
+                let next_attempt = storage.outbox.next_attempt();
+                drop(storage);
 
-    let identity_key = *storage.store.get_identity_key_pair(None).await.unwrap().identity_key();
+                _self.schedule_outbox(next_attempt);
+            }
 
-    for i in bundle_id..bundle_id+5 {
-        let pre_key_id = i;
-        let pre_key_pair = KeyPair::generate(&mut csprng);
+            Ok(JsValue::undefined())
+        });
+    }
 
-        storage.store.save_pre_key(pre_key_id, &PreKeyRecord::new(pre_key_id, &pre_key_pair), None).await.unwrap();
-
-        let pre_key_bundle: PreKeyBundleSerde = PreKeyBundle::new(
-            storage.store.get_local_registration_id(None).await.unwrap(),
-            1,
-            Some((pre_key_id, pre_key_pair.public_key)),
-            signed_pre_key_id,
-            signed_pre_key_record.public_key().unwrap(),
-            signed_pre_key_record.signature().unwrap(),
-            identity_key,
-        ).unwrap().into();
-        let bundle = base64::encode(pre_key_bundle.serialize());
-        let payload = format!("{{\"bundle_id\": {}, \"bundle\": \"{}\" }}", pre_key_id, bundle);
-
-        request("POST".to_string(), format!("{}/protocol/bundles", storage.api_basepath), Some(payload)).await;
-    };
-}
+    /*
+     * Retry everything in the outbox right away, i.e. when the browser comes back online
+     *
+     * Resolves to an object of id: event, for every message that was sent or given up on. The
+     * events are the same as the ones passed to the outbox callback.
+     */
+    pub fn flush_outbox(&self) -> Promise {
+        let _self = self.inner.clone();
 
-#[wasm_bindgen]
-impl Protocol {
-    #[wasm_bindgen(constructor)]
-    pub fn new() -> Protocol {
-        console_error_panic_hook::set_once();
+        wasm_bindgen_futures::future_to_promise(async move {
+            match _self.flush_outbox(true).await {
+                Ok(flushed) => Ok(flushed.into()),
+                Err(e) => Err(e.into())
+            }
+        })
+    }
 
-        Protocol {
-            inner: Arc::new(ProtocolInner {
-                storage: RefCell::new(None),
-                timeout: RefCell::new(None),
-                message_ids: RefCell::new(Vec::new())
-            })
-        }
+    pub fn encrypt_bytes(&self, user_id: String, message: Vec<u8>) -> Promise {
+        self.encrypt_content(user_id, message::BINARY.to_string(), message)
     }
 
-    pub fn init(&self, secret_key: String, api_basepath: JsValue) -> Promise {
+    /*
+     * Encrypt a message with an explicit content type, which is encrypted along with the body
+     */
+    pub fn encrypt_content(&self, user_id: String, content_type: String, body: Vec<u8>) -> Promise {
         let _self = self.inner.clone();
-
-        let basepath = if !api_basepath.is_undefined() && api_basepath.is_string() {
-            api_basepath.as_string().unwrap()
-        } else {
-            option_env!("API_BASEPATH").unwrap_or("http://localhost:5000").to_string()
+        let done = async move {
+            match _self.encrypt(&user_id, &Content::new(&content_type, body)).await {
+                Ok((ciphertexts, errors)) => Ok(encrypted_object(&ciphertexts, &errors).into()),
+                Err(e) => Err(e.into())
+            }
         };
 
-        let done = async move {
-            let storage = SyncableStore::new(secret_key, basepath).await;
+        self.schedule_sync();
 
-            _self.storage.replace(Some(storage));
+        wasm_bindgen_futures::future_to_promise(done)
+    }
 
-            Ok(JsValue::undefined())
+    pub fn decrypt(&self, user_id: String, device_id: u32, message_id: String, message: String) -> Promise {
+        let address = ProtocolAddress::new(user_id, device_id);
+
+        let _self = self.inner.clone();
+        let done = async move {
+            match _self.decrypt(&address, message_id, &message).await {
+                Ok(decrypted) => Ok(JsValue::from_str(&to_utf8(decrypted.content.body)?)),
+                Err(e) => Err(e.into())
+            }
         };
 
+        self.schedule_sync();
+
         wasm_bindgen_futures::future_to_promise(done)
     }
 
-    pub fn register(&self, secret_key: String, api_basepath: JsValue) -> Promise {
-        let _self = self.inner.clone();
+    /*
+     * Same as `decrypt`, but resolves to a Uint8Array for messages that are not text
+     */
+    pub fn decrypt_bytes(&self, user_id: String, device_id: u32, message_id: String, message: String) -> Promise {
+        let address = ProtocolAddress::new(user_id, device_id);
 
-        let basepath = if !api_basepath.is_undefined() && api_basepath.is_string() {
-            api_basepath.as_string().unwrap()
-        } else {
-            option_env!("API_BASEPATH").unwrap_or("http://localhost:5000").to_string()
+        let _self = self.inner.clone();
+        let done = async move {
+            match _self.decrypt(&address, message_id, &message).await {
+                Ok(decrypted) => Ok(js_sys::Uint8Array::from(&decrypted.content.body[..]).into()),
+                Err(e) => Err(e.into())
+            }
         };
 
+        self.schedule_sync();
+
+        wasm_bindgen_futures::future_to_promise(done)
+    }
+
+    /*
+     * Same as `decrypt`, but resolves to {content_type, body, timestamp}
+     *
+     * The body is a Uint8Array. The timestamp is the sender's, and only known for messages
+     * from version 2 envelopes on. Messages from before the envelope are always
+     * "application/octet-stream".
+     */
+    pub fn decrypt_content(&self, user_id: String, device_id: u32, message_id: String, message: String) -> Promise {
+        let address = ProtocolAddress::new(user_id, device_id);
+
+        let _self = self.inner.clone();
         let done = async move {
-            let mut storage = SyncableStore::register(secret_key, basepath);
+            match _self.decrypt(&address, message_id, &message).await {
+                Ok(decrypted) => {
+                    let obj = js_sys::Object::new();
+                    js_sys::Reflect::set(&obj, &"content_type".into(), &decrypted.content.content_type.into()).unwrap();
+                    js_sys::Reflect::set(&obj, &"body".into(), &js_sys::Uint8Array::from(&decrypted.content.body[..])).unwrap();
+                    js_sys::Reflect::set(&obj, &"timestamp".into(), &decrypted.timestamp.map(|t| JsValue::from_f64(t as f64)).unwrap_or(JsValue::undefined())).unwrap();
+
+                    Ok(obj.into())
+                },
+                Err(e) => Err(e.into())
+            }
+        };
 
-            // Generate and publish some bundles
-            gen_pre_key_bundles(&mut storage).await;
+        self.schedule_sync();
 
-            // Share our identity public key
-            let identity_key = base64::encode(storage.store.identity_store.get_identity_key_pair(None).await.unwrap().public_key().serialize());
+        wasm_bindgen_futures::future_to_promise(done)
+    }
 
-            // Save state
-            storage.sync(None).await;
+    /*
+     * Handle session resets, called as callback(user_id, device_id, message)
+     *
+     * When a message fails to decrypt because the session is broken, decrypt rejects with
+     * SessionBroken and a new session is set up with the sender. The app should deliver
+     * `message` to that device, which passes it to `process_session_reset`. Without a callback
+     * broken sessions are left as is.
+     */
+    pub fn on_session_reset(&self, callback: js_sys::Function) {
+        self.inner.reset_callback.replace(Some(callback));
+    }
 
-            _self.storage.replace(Some(storage));
+    /*
+     * Take in a session reset from the device, resolves to the id of the message to resend
+     */
+    pub fn process_session_reset(&self, user_id: String, device_id: u32, message_id: String, message: String) -> Promise {
+        let address = ProtocolAddress::new(user_id, device_id);
 
-            Ok(JsValue::from_str(&identity_key))
+        let _self = self.inner.clone();
+        let done = async move {
+            match _self.decrypt(&address, message_id, &message).await.and_then(|decrypted| parse_session_reset(&decrypted.content)) {
+                Ok(message_id) => Ok(JsValue::from_str(&message_id)),
+                Err(e) => Err(e.into())
+            }
         };
 
+        self.schedule_sync();
+
         wasm_bindgen_futures::future_to_promise(done)
     }
 
-    pub fn add_pre_key_bundles(&self) -> Promise {
+    /*
+     * Set the public key of the server that certificates for sealed sender are issued under
+     */
+    pub fn set_trust_root(&self, trust_root: String) -> Result<(), JsValue> {
+        let trust_root = base64::decode(&trust_root).map_err(|_| Error::Malformed("Trust root is not valid base64".to_string()))?;
+        let trust_root = PublicKey::deserialize(&trust_root).map_err(Error::from)?;
+
+        self.inner.trust_root.replace(Some(trust_root));
+
+        Ok(())
+    }
+
+    /*
+     * Fetch our sender certificate from the server and check it against the trust root
+     *
+     * Certificates expire, so this should be called again before that happens. Resolves to
+     * the expiration time in ms.
+     */
+    pub fn load_sender_certificate(&self) -> Promise {
         let _self = self.inner.clone();
 
         wasm_bindgen_futures::future_to_promise(async move {
-            match _self.storage.try_borrow_mut().map(|mut s| s.take().unwrap()) {
-                Ok(mut storage) => {
-                    gen_pre_key_bundles(&mut storage).await;
-                    storage.sync(None).await;
+            let loaded: Result<SenderCertificate, Error> = async {
+                let trust_root = _self.trust_root()?;
+                let storage = _self.clone_storage().await?;
+                let certificate = fetch_sender_certificate(&storage).await?;
 
-                    _self.storage.replace(Some(storage));
+                validate_sender_certificate(&storage, &certificate, &trust_root, now()).await?;
 
-                    Ok(JsValue::undefined())
+                Ok(certificate)
+            }.await;
+
+            match loaded {
+                Ok(certificate) => {
+                    let expiration = certificate.expiration().map_err(Error::from)?;
+                    _self.sender_certificate.replace(Some(certificate));
+
+                    Ok(JsValue::from_f64(expiration as f64))
                 },
-                Err(_) => Err(Error::new("Cannot add pre key bundles: storage is already borrowed").into())
+                Err(e) => Err(e.into())
             }
         })
     }
 
-    pub fn get_fingerprint(&self, our_id: String, their_id: String) -> Promise {
-        let maybe_store = self.inner.storage.try_borrow().map(|s| s.clone()).unwrap_or(None);
-        let address = ProtocolAddress::new(their_id.clone(), 1);
+    /*
+     * Seal outgoing messages, so that the server cannot see who sent them
+     *
+     * Needs a sender certificate, see `load_sender_certificate`. Sealed messages are decrypted
+     * with `decrypt_sealed` on the receiving end.
+     */
+    pub fn set_sealed_sender(&self, enabled: bool) {
+        self.inner.sealed_sender.replace(enabled);
+    }
 
-        wasm_bindgen_futures::future_to_promise(async move {
-            match maybe_store {
-                Some(storage) => {
-                    let our_identity_key = storage.store.identity_store.get_identity_key_pair(None).await.unwrap().identity_key().clone();
+    /*
+     * Decrypt a sealed sender message, resolves to {user_id, device_id, content_type, body, timestamp}
+     *
+     * The sender is only known after decrypting, and is authenticated by their certificate.
+     */
+    pub fn decrypt_sealed(&self, message_id: String, message: String) -> Promise {
+        let _self = self.inner.clone();
+        let done = async move {
+            match _self.decrypt_sealed(message_id, &message).await {
+                Ok((address, decrypted)) => {
+                    let obj = js_sys::Object::new();
+                    js_sys::Reflect::set(&obj, &"user_id".into(), &address.name().into()).unwrap();
+                    js_sys::Reflect::set(&obj, &"device_id".into(), &address.device_id().into()).unwrap();
+                    js_sys::Reflect::set(&obj, &"content_type".into(), &decrypted.content.content_type.into()).unwrap();
+                    js_sys::Reflect::set(&obj, &"body".into(), &js_sys::Uint8Array::from(&decrypted.content.body[..])).unwrap();
+                    js_sys::Reflect::set(&obj, &"timestamp".into(), &decrypted.timestamp.map(|t| JsValue::from_f64(t as f64)).unwrap_or(JsValue::undefined())).unwrap();
+
+                    Ok(obj.into())
+                },
+                Err(e) => Err(e.into())
+            }
+        };
 
-                    match storage.store.identity_store.get_identity(&address, None).await.unwrap() {
-                        Some(their_identity_key) => {
-                            let fprint = Fingerprint::new(2, 5200, our_id.as_bytes(), &our_identity_key, their_id.as_bytes(), &their_identity_key).unwrap().display;
+        self.schedule_sync();
 
-                            Ok(JsValue::from_str(&fprint.to_string()))
-                        },
-                        None => Ok(JsValue::undefined())
-                    }
-                },
-                None => Err(Error::new("Cannot get fingerprint: storage is mutably borrowed").into())
+        wasm_bindgen_futures::future_to_promise(done)
+    }
+
+    /*
+     * Create a new group, identified by its distribution id
+     */
+    pub fn create_group(&self) -> String {
+        Uuid::new_v4().to_string()
+    }
+
+    /*
+     * Share our sender key for a group with all devices of the given members
+     *
+     * The distribution message is sent over the regular 1:1 sessions and should be handed to
+     * `process_sender_key` by the receiving end. Our own other devices are included under
+     * `our_id`. Resolves to {ciphertexts, errors}, with ciphertexts as
+     * user_id: {device_id: ciphertext} and errors as user_id: Error for the members it failed for,
+     * or user_id: {device_id: Error} when only some of their devices failed.
+     */
+    pub fn distribute_sender_key(&self, our_id: String, distribution_id: String, user_ids: JsValue) -> Promise {
+        let _self = self.inner.clone();
+        let done = async move {
+            let distribution_id = Uuid::parse_str(&distribution_id).map_err(|_| Error::Malformed("Invalid distribution id".to_string()))?;
+            let user_ids: Vec<String> = serde_wasm_bindgen::from_value(user_ids).map_err(|_| Error::Malformed("Expected a list of user ids".to_string()))?;
+
+            match _self.distribute_sender_key(&our_id, distribution_id, &user_ids).await {
+                Ok((ciphertexts, errors)) => Ok(encrypted_object(&ciphertexts, &errors).into()),
+                Err(e) => Err(e.into())
             }
-        })
+        };
+
+        self.schedule_sync();
+
+        wasm_bindgen_futures::future_to_promise(done)
     }
 
-    pub fn encrypt(&self, user_id: String, message: String) -> Promise {
-        let mut csprng = OsRng;
-        let address = ProtocolAddress::new(user_id.clone(), 1);
+    /*
+     * Decrypt and store a sender key that was distributed by another group member
+     *
+     * Rejects with Malformed, leaving the message as is, when it is not a sender key.
+     */
+    pub fn process_sender_key(&self, user_id: String, device_id: u32, message: String) -> Promise {
+        let address = ProtocolAddress::new(user_id.clone(), device_id);
 
         let _self = self.inner.clone();
         let done = async move {
-            match _self.storage.try_borrow_mut().map(|mut s| s.take().unwrap()) {
+            let distribution_message = match _self.decrypt_as(&address, String::new(), &message, message::SENDER_KEY).await {
+                Ok(decrypted) => SenderKeyDistributionMessage::try_from(&decrypted.content.body[..]).map_err(|e| JsValue::from(decrypt_error(e)))?,
+                Err(e) => return Err(e.into())
+            };
+
+            match _self.take_storage().await {
                 Ok(mut storage) => {
-                    // No existing session means we need to fetch a pre_key_bundle
-                    if storage.store.session_store.load_session(&address, None).await.unwrap().is_none() {
-                        let response = request("GET".to_string(), format!("{}/protocol/bundles/{}", storage.api_basepath, &user_id), None).await; // assume it has a bundle
-                        let bundle_id = response.as_f64().unwrap() as u32;
-
-                        let bundle = request("DELETE".to_string(), format!("{}/protocol/bundles/{}/{}", storage.api_basepath, &user_id, &bundle_id), None).await.as_string().unwrap();
-                        let pre_key_bundle: PreKeyBundle = PreKeyBundleSerde::deserialize(&base64::decode(&bundle).unwrap()[..]).into();
-
-                        // Create the session
-                        process_prekey_bundle(
-                            &address,
-                            &mut storage.store.session_store,
-                            &mut storage.store.identity_store,
-                            &pre_key_bundle,
-                            &mut csprng,
-                            None,
-                        ).await.unwrap();
+                    let maybe_processed = process_sender_key_distribution_message(&address, &distribution_message, &mut storage.sender_key_store, None).await;
+
+                    match maybe_processed {
+                        Ok(_) => Ok(JsValue::undefined()),
+                        Err(e) => Err(Error::from(e).into())
                     }
+                },
+                Err(e) => Err(e.into())
+            }
+        };
+
+        self.schedule_sync();
+
+        wasm_bindgen_futures::future_to_promise(done)
+    }
 
-                    let encrypted = message_encrypt(message.as_bytes(), &address, &mut storage.store.session_store, &mut storage.store.identity_store, None).await.unwrap();
+    /*
+     * Encrypt a message once for the whole group
+     *
+     * The sender key must have been distributed to all members beforehand. Just like `encrypt`,
+     * the message is padded and wrapped in an envelope.
+     */
+    pub fn group_encrypt(&self, our_id: String, distribution_id: String, message: String) -> Promise {
+        let _self = self.inner.clone();
+        let done = async move {
+            let distribution_id = match Uuid::parse_str(&distribution_id) {
+                Ok(distribution_id) => distribution_id,
+                Err(_) => return Err(Error::Malformed("Invalid distribution id".to_string()).into())
+            };
 
-                    _self.storage.replace(Some(storage));
+            match _self.take_storage().await {
+                Ok(mut storage) => {
+                    let sender = ProtocolAddress::new(our_id, storage.device_id);
+                    let content = Content::new(message::TEXT, message.into_bytes());
 
-                    Ok(JsValue::from_str(&base64::encode(&encrypted.serialize())))
+                    match encrypt_group_message(&mut storage, &sender, distribution_id, &content, now()).await {
+                        Ok(encrypted) => Ok(JsValue::from_str(&encrypted)),
+                        Err(e) => Err(e.into())
+                    }
                 },
-                Err(_) => Err(Error::new("Cannot encrypt message: storage is already borrowed").into())
+                Err(e) => Err(e.into())
             }
         };
 
@@ -235,60 +2124,94 @@ impl Protocol {
         wasm_bindgen_futures::future_to_promise(done)
     }
 
-    pub fn decrypt(&self, user_id: String, message_id: String, message: String) -> Promise {
-        let mut csprng = OsRng;
-        let address = ProtocolAddress::new(user_id.clone(), 1);
+    pub fn group_decrypt(&self, user_id: String, device_id: u32, message: String) -> Promise {
+        let address = ProtocolAddress::new(user_id.clone(), device_id);
 
         let _self = self.inner.clone();
         let done = async move {
-            let maybe_message_ids = _self.message_ids.try_borrow_mut();
-            if maybe_message_ids.is_ok() && !message_id.is_empty() {
-                maybe_message_ids.unwrap().push(message_id);
+            match _self.take_storage().await {
+                Ok(mut storage) => {
+                    match decrypt_group_message(&mut storage, &address, &message).await {
+                        Ok(decrypted) => Ok(JsValue::from_str(&to_utf8(decrypted.content.body)?)),
+                        Err(e) => Err(e.into())
+                    }
+                },
+                Err(e) => Err(e.into())
             }
+        };
 
-            match _self.storage.try_borrow_mut().map(|mut s| s.take().unwrap()) {
-                Ok(mut storage) => {
-                    let session_exists = storage.store.session_store.load_session(&address, None).await.unwrap();
-
-                    let bytes = base64::decode(&message).unwrap();
-                    let ctext = match session_exists {
-                        Some(_) => {
-                            // Prekey messages may be queued up, maybe fallback to prekey type
-                            match SignalMessage::try_from(&bytes[..]) {
-                                Ok(message) => CiphertextMessage::SignalMessage(message),
-                                Err(_) => CiphertextMessage::PreKeySignalMessage(PreKeySignalMessage::try_from(&bytes[..]).unwrap())
-                            }
-                        },
-                        None => CiphertextMessage::PreKeySignalMessage(PreKeySignalMessage::try_from(&bytes[..]).unwrap()),
-                    };
+        self.schedule_sync();
 
-                    let maybe_decrypted = message_decrypt(
-                        &ctext,
-                        &address,
-                        &mut storage.store.session_store,
-                        &mut storage.store.identity_store,
-                        &mut storage.store.pre_key_store,
-                        &mut storage.store.signed_pre_key_store,
-                        &mut csprng,
-                        None,
-                    ).await;
-
-                    _self.storage.replace(Some(storage));
-
-                    match maybe_decrypted {
-                        Ok(decrypted) => {
-                            Ok(JsValue::from_str(&String::from_utf8(decrypted).unwrap()))
-                        },
-                        Err(SignalProtocolError::DuplicatedMessage(_, _)) => {
-                            Err(JsValue::from_str(&"DuplicatedMessageError".to_owned()))
-                        },
-                        Err(e) => {
-                            console::log_2(&"Error when decrypting message: ".into(), &e.to_string().into());
-                            Err(JsValue::from_str(&"MessageDecryptError".to_owned()))
-                        }
-                    }
+        wasm_bindgen_futures::future_to_promise(done)
+    }
+
+    /*
+     * Share a key from the keystore with all devices of another user
+     *
+     * The key is wrapped in a signed envelope that the receiving end hands to
+     * `accept_shared_key`. Resolves to {ciphertexts, errors}, the same as `encrypt`
+     */
+    pub fn share_key(&self, keystore: &KeyStore, our_id: String, key_id: String, recipient: String, purpose: String) -> Promise {
+        let _self = self.inner.clone();
+        let keystore = keystore.inner();
+
+        let done = async move {
+            let shared: Result<(js_sys::Object, js_sys::Object), Error> = async {
+                let shared_key = SharedKey::new(key_id.clone(), keystore.get_key(&key_id)?, our_id, recipient.clone(), purpose)?;
+                let key_pair = _self.clone_storage().await?.identity_store.get_identity_key_pair(None).await?;
+
+                _self.encrypt(&recipient, &Content::new(message::SHARED_KEY, shared_key.sign(&key_pair)?.into_bytes())).await
+            }.await;
+
+            match shared {
+                Ok((ciphertexts, errors)) => Ok(encrypted_object(&ciphertexts, &errors).into()),
+                Err(e) => Err(e.into())
+            }
+        };
+
+        self.schedule_sync();
+
+        wasm_bindgen_futures::future_to_promise(done)
+    }
+
+    /*
+     * Decrypt a key shared by another user, verify it and import it into the keystore
+     *
+     * Returns an object with the key_id and purpose of the key. Rejects with Malformed, leaving
+     * the message as is, when it is not a shared key.
+     */
+    pub fn accept_shared_key(&self, keystore: &KeyStore, our_id: String, user_id: String, device_id: u32, message_id: String, message: String) -> Promise {
+        let address = ProtocolAddress::new(user_id.clone(), device_id);
+
+        let _self = self.inner.clone();
+        let keystore = keystore.inner();
+
+        let done = async move {
+            let accepted: Result<SharedKey, Error> = async {
+                let decrypted = _self.decrypt_as(&address, message_id, &message, message::SHARED_KEY).await?;
+                let identity_key = _self.clone_storage().await?.identity_store.get_identity(&address, None).await?
+                    .ok_or_else(|| Error::IdentityNotFound(user_id.clone()))?;
+
+                let shared_key = SharedKey::verify(&decrypted.content.body, identity_key.public_key())?;
+
+                if shared_key.sender != user_id || shared_key.recipient != our_id {
+                    return Err(Error::Protocol("Shared key was not meant for us".to_string()));
+                }
+
+                keystore.import_key(shared_key.key_id.clone(), shared_key.key.clone()).await?;
+
+                Ok(shared_key)
+            }.await;
+
+            match accepted {
+                Ok(shared_key) => {
+                    let obj = js_sys::Object::new();
+                    js_sys::Reflect::set(&obj, &"key_id".into(), &shared_key.key_id.into()).unwrap();
+                    js_sys::Reflect::set(&obj, &"purpose".into(), &shared_key.purpose.into()).unwrap();
+
+                    Ok(obj.into())
                 },
-                Err(_) => Err(Error::new("Cannot decrypt message: storage is already borrowed").into())
+                Err(e) => Err(e.into())
             }
         };
 
@@ -299,40 +2222,38 @@ impl Protocol {
 
     pub fn sign(&self, message: String) -> Promise {
         let mut csprng = OsRng;
-        let maybe_store = self.inner.storage.try_borrow().map(|s| s.clone()).unwrap_or(None);
+        let _self = self.inner.clone();
 
         wasm_bindgen_futures::future_to_promise(async move {
-            match maybe_store {
-                Some(storage) => {
-                    let priv_key = storage.store.identity_store.get_identity_key_pair(None).await.unwrap().private_key().clone();
-                    let signature = priv_key.calculate_signature(message.as_bytes(), &mut csprng).unwrap();
+            match _self.clone_storage().await {
+                Ok(storage) => {
+                    let priv_key = storage.identity_store.get_identity_key_pair(None).await.map_err(Error::from)?.private_key().clone();
+                    let signature = priv_key.calculate_signature(message.as_bytes(), &mut csprng).map_err(Error::from)?;
 
                     Ok(hex::encode(signature).into())
                 },
-                None => Err(Error::new("Cannot calculate signature: storage is mutably borrowed").into())
+                Err(e) => Err(e.into())
             }
         })
     }
 
     pub fn verify(&self, message: String, signature: String, user_id: JsValue) -> Promise {
-        let maybe_store = self.inner.storage.try_borrow().map(|s| s.clone()).unwrap_or(None);
+        let _self = self.inner.clone();
 
         // Optionally give the user id to verify against. When verifiying our own signature,
         // this should be left empty.
-        let address = if !user_id.is_undefined() && user_id.is_string() {
-            Some(ProtocolAddress::new(user_id.as_string().unwrap(), 1))
+        let their_id = if !user_id.is_undefined() && user_id.is_string() {
+            user_id.as_string()
         } else {
             None
         };
 
         wasm_bindgen_futures::future_to_promise(async move {
-            match maybe_store {
-                Some(storage) => {
-                    let maybe_pub_key = match address {
-                        Some(their_address) => {
-                            storage.store.identity_store.get_identity(&their_address, None).await.unwrap().map(|x| x.public_key().clone())
-                        },
-                        None => Some(storage.store.identity_store.get_identity_key_pair(None).await.unwrap().public_key().clone())
+            match _self.clone_storage().await {
+                Ok(storage) => {
+                    let maybe_pub_key = match their_id.as_ref() {
+                        Some(their_id) => storage.identity_store.user_identity(their_id).map(|x| x.public_key().clone()),
+                        None => Some(storage.identity_store.get_identity_key_pair(None).await.map_err(Error::from)?.public_key().clone())
                     };
 
                     match maybe_pub_key {
@@ -344,19 +2265,19 @@ impl Protocol {
                                         _ => Ok(false.into())
                                     }
                                 },
-                                Err(_) => Err(Error::new("Signature is not in hex").into())
+                                Err(_) => Err(Error::Malformed("Signature is not in hex".to_string()).into())
                             }
                         },
-                        None => Err(Error::new("Cannot find public key for this user").into())
+                        None => Err(Error::IdentityNotFound(their_id.unwrap_or_default()).into())
                     }
                 },
-                None => Err(Error::new("Cannot verify signature: storage is mutably borrowed").into())
+                Err(e) => Err(e.into())
             }
         })
     }
 
     pub fn sync(&self) -> Promise {
-        let maybe_store = self.inner.storage.try_borrow().map(|s| s.clone()).unwrap_or(None);
+        let _self = self.inner.clone();
 
         let maybe_message_ids = self.inner.message_ids.try_borrow_mut();
         let message_ids = if maybe_message_ids.is_ok() {
@@ -369,19 +2290,23 @@ impl Protocol {
         };
 
         wasm_bindgen_futures::future_to_promise(async move {
-            match maybe_store {
-                Some(store) => {
-                    store.sync(Some(message_ids)).await;
-
-                    Ok(JsValue::undefined())
+            match _self.clone_storage().await {
+                Ok(store) => {
+                    match store.sync(Some(message_ids)).await {
+                        Ok(_) => Ok(JsValue::undefined()),
+                        Err(e) => Err(e.into())
+                    }
                 },
-                None => Err(Error::new("Cannot sync store: storage is mutably borrowed").into())
+                Err(e) => Err(e.into())
             }
         })
     }
 
     pub fn schedule_sync(&self) -> () {
-        let window = web_sys::window().unwrap();
+        let window = match web_sys::window() {
+            Some(window) => window,
+            None => return
+        };
 
         // Only create a timeout callback when there is no handle to an existing one
         if self.inner.timeout.try_borrow().map(|t| t.is_none()).unwrap_or(false) {
@@ -397,32 +2322,341 @@ impl Protocol {
                     vec![]
                 };
 
-                match _self.storage.try_borrow().map(|s| s.clone().unwrap()) {
-                    Ok(storage) => {
-                        // Unset timeout "lock"
-                        _self.timeout.try_borrow_mut().map(|mut t| t.take()).ok();
-
-                        let _obj: &js_sys::Object = wasm_bindgen_futures::future_to_promise(async move {
-                            storage.sync(Some(message_ids)).await;
-
-                            Ok(JsValue::undefined())
-                        }).as_ref();
-                    },
-                    Err(_) => {}
-                };
+                // Unset timeout "lock"
+                _self.timeout.try_borrow_mut().map(|mut t| t.take()).ok();
+
+                // Syncs after the operations that are still queued
+                let inner = _self.clone();
+                let _obj: &js_sys::Object = wasm_bindgen_futures::future_to_promise(async move {
+                    match inner.clone_storage().await {
+                        Ok(storage) => {
+                            match storage.sync(Some(message_ids)).await {
+                                Ok(_) => Ok(JsValue::undefined()),
+                                Err(e) => Err(e.into())
+                            }
+                        },
+                        Err(e) => Err(e.into())
+                    }
+                }).as_ref();
             }) as Box<dyn FnMut()>);
-            let handle = window.set_timeout_with_callback_and_timeout_and_arguments_0(&f.as_ref().unchecked_ref(), 3_000).unwrap();
-            self.inner.timeout.try_borrow_mut().map(|mut t| t.replace(handle)).ok();
+            // Without a timeout the message ids stay queued, the next call tries again
+            match window.set_timeout_with_callback_and_timeout_and_arguments_0(&f.as_ref().unchecked_ref(), 3_000) {
+                Ok(handle) => {
+                    self.inner.timeout.try_borrow_mut().map(|mut t| t.replace(handle)).ok();
 
-            // TODO: this leaks memory
-            f.forget();
+                    // TODO: this leaks memory
+                    f.forget();
+                },
+                Err(e) => console::log_2(&"Cannot schedule sync: ".into(), &e)
+            }
         }
     }
 }
 
+impl Protocol {
+    /*
+     * Use a custom transport instead of fetch, any api basepath given later on is ignored
+     */
+    pub fn with_transport(transport: Rc<dyn Transport>) -> Protocol {
+        let protocol = Protocol::new();
+        protocol.inner.transport.replace(Some(transport));
+
+        protocol
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use futures_util::FutureExt;
+    use crate::transport::MemoryTransport;
+    use crate::storage::IdentityStatus;
+    use crate::crypto::PADDING_BLOCK_SIZE;
+
+    #[test]
+    fn test_rotate_signed_pre_key() {
+        async {
+            let mut storage = SyncableStore::register(hex::encode([1u8; 32]), Rc::new(MemoryTransport::new()), 1, None).unwrap();
+            let policy = RotationPolicy { max_age: 100, grace_period: 50 };
+
+            assert_eq!(rotate_signed_pre_key(&mut storage, policy, 0).await.unwrap(), (1, true));
+            assert_eq!(rotate_signed_pre_key(&mut storage, policy, 99).await.unwrap(), (1, false));
+            assert_eq!(rotate_signed_pre_key(&mut storage, policy, 100).await.unwrap(), (2, true));
+
+            // The previous key is kept for the grace period
+            assert!(storage.get_signed_pre_key(1, None).await.is_ok());
+            assert_eq!(rotate_signed_pre_key(&mut storage, policy, 151).await.unwrap(), (2, false));
+            assert!(storage.get_signed_pre_key(1, None).await.is_err());
+
+            // Nonsense from JS does not turn into a policy that rotates on every call
+            assert!(RotationPolicy::from_js(100.0, 50.0).is_ok());
+            assert!(RotationPolicy::from_js(f64::NAN, 50.0).is_err());
+            assert!(RotationPolicy::from_js(0.0, 50.0).is_err());
+            assert!(RotationPolicy::from_js(100.0, -1.0).is_err());
+            assert!(RotationPolicy::from_js(100.0, f64::INFINITY).is_err());
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn test_get_device_ids() {
+        async {
+            let transport = Rc::new(MemoryTransport::new());
+            let transport_dyn: Rc<dyn Transport> = transport.clone();
+            let bob = "bob".to_string();
+
+            transport.respond("GET", "/protocol/devices/bob", json!([1, 2]));
+            assert_eq!(get_device_ids(&transport_dyn, Some(&bob)).await.unwrap(), vec![1, 2]);
+
+            // A broken response is not the same as having no devices
+            transport.respond("GET", "/protocol/devices/bob", json!({"devices": [1, 2]}));
+            assert!(matches!(get_device_ids(&transport_dyn, Some(&bob)).await, Err(Error::Malformed(_))));
+
+            transport.respond("GET", "/protocol/devices/bob", json!([1, "2"]));
+            assert!(matches!(get_device_ids(&transport_dyn, Some(&bob)).await, Err(Error::Malformed(_))));
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn test_replenish_pre_key_bundles() {
+        async {
+            let transport = Rc::new(MemoryTransport::new());
+            let mut storage = SyncableStore::register(hex::encode([1u8; 32]), transport.clone(), 1, None).unwrap();
+            let policy = RotationPolicy::default();
+            let posted = || transport.requests().iter().filter(|(method, _, _)| method == "POST").count();
+
+            // The first signed pre key comes with a full set of bundles, and a last resort bundle
+            transport.respond("DELETE", "/protocol/bundles?device_id=1", serde_json::json!([]));
+
+            assert_eq!(replenish_pre_key_bundles(&mut storage, policy, 10, 0).await.unwrap(), 10);
+            assert_eq!(posted(), 10);
+
+            let last_resort = transport.requests().into_iter().find(|(_, path, _)| path == "/protocol/bundles/last-resort").unwrap();
+            let bundle: serde_json::Value = serde_json::from_str(&last_resort.2.unwrap()).unwrap();
+            let bundle = PreKeyBundleSerde::deserialize(&base64::decode(bundle["bundle"].as_str().unwrap()).unwrap()).unwrap();
+
+            assert_eq!(PreKeyBundle::try_from(bundle).unwrap().pre_key_id().unwrap(), None);
+
+            transport.respond("GET", "/protocol/bundles?device_id=1", serde_json::json!(10));
+            transport.respond("GET", "/protocol/bundles/count?device_id=1", serde_json::json!(7));
+
+            assert_eq!(replenish_pre_key_bundles(&mut storage, policy, 10, 1).await.unwrap(), 3);
+            assert_eq!(posted(), 13);
+            assert!(storage.get_pre_key(13, None).await.is_ok());
+
+            transport.respond("GET", "/protocol/bundles/count?device_id=1", serde_json::json!(10));
+
+            assert_eq!(replenish_pre_key_bundles(&mut storage, policy, 10, 1).await.unwrap(), 0);
+
+            // On rotation the unclaimed bundles are retired and replaced, rather than added to
+            transport.respond("GET", "/protocol/bundles?device_id=1", serde_json::json!(13));
+            transport.respond("DELETE", "/protocol/bundles?device_id=1", serde_json::json!([1, 2]));
+
+            assert_eq!(replenish_pre_key_bundles(&mut storage, policy, 10, policy.max_age).await.unwrap(), 10);
+            assert!(storage.get_pre_key(1, None).await.is_err());
+            assert!(storage.get_pre_key(3, None).await.is_ok());
+            assert!(storage.get_pre_key(23, None).await.is_ok());
+            assert_eq!(storage.signed_pre_key_store.current().unwrap(), Some((2, policy.max_age)));
+
+            // An unknown count is not taken as zero
+            transport.respond("GET", "/protocol/bundles/count?device_id=1", serde_json::json!("many"));
+
+            assert!(matches!(replenish_pre_key_bundles(&mut storage, policy, 10, policy.max_age).await, Err(Error::Malformed(_))));
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn test_compare_scannable_fingerprint() {
+        async {
+            let mut csprng = OsRng;
+            let transport = Rc::new(MemoryTransport::new());
+            let mut alice = SyncableStore::register(hex::encode([1u8; 32]), transport.clone(), 1, None).unwrap();
+            let mut bob = SyncableStore::register(hex::encode([2u8; 32]), transport.clone(), 1, None).unwrap();
+
+            let alice_key = *alice.get_identity_key_pair(None).await.unwrap().identity_key();
+            let bob_key = *bob.get_identity_key_pair(None).await.unwrap().identity_key();
+            alice.save_identity(&ProtocolAddress::new("bob".to_owned(), 1), &bob_key, None).await.unwrap();
+            bob.save_identity(&ProtocolAddress::new("alice".to_owned(), 1), &alice_key, None).await.unwrap();
+
+            let alice_id = "alice".to_string();
+            let bob_id = "bob".to_string();
+            let scanned = get_fingerprint(&bob, &bob_id, &alice_id).await.unwrap().unwrap().scannable.serialize().unwrap();
+
+            // A code for someone else's key does not match
+            let mallory_key = *IdentityKeyPair::generate(&mut csprng).identity_key();
+            let mallory = Fingerprint::new(2, 5200, bob_id.as_bytes(), &mallory_key, alice_id.as_bytes(), &alice_key).unwrap();
+
+            assert!(!compare_scannable_fingerprint(&mut alice, &alice_id, &bob_id, &mallory.scannable.serialize().unwrap()).await.unwrap());
+            assert_eq!(alice.identity_store.identity_status("bob"), Some(IdentityStatus::Unverified));
+
+            assert!(compare_scannable_fingerprint(&mut alice, &alice_id, &bob_id, &scanned).await.unwrap());
+            assert_eq!(alice.identity_store.identity_status("bob"), Some(IdentityStatus::Verified));
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn test_reset_session() {
+        async {
+            let mut csprng = OsRng;
+            let transport = Rc::new(MemoryTransport::new());
+            let mut alice = SyncableStore::register(hex::encode([1u8; 32]), transport.clone(), 1, None).unwrap();
+            let mut bob = SyncableStore::register(hex::encode([2u8; 32]), transport.clone(), 1, None).unwrap();
+            let alice_address = ProtocolAddress::new("alice".to_owned(), 1);
+            let bob_address = ProtocolAddress::new("bob".to_owned(), 1);
+
+            // Alice's bundle is the only one Bob can claim
+            let (signed_pre_key_id, _) = rotate_signed_pre_key(&mut alice, RotationPolicy::default(), 0).await.unwrap();
+            publish_last_resort_bundle(&alice, signed_pre_key_id).await.unwrap();
+            let (_, _, body) = transport.requests().pop().unwrap();
+            let bundle: serde_json::Value = serde_json::from_str(&body.unwrap()).unwrap();
+            transport.respond("GET", "/protocol/bundles/alice/1/last-resort", bundle["bundle"].clone());
+
+            let envelope = Envelope::decode(&reset_session(&mut bob, &alice_address, "message", 100).await.unwrap()).unwrap();
+            assert_eq!((envelope.message_type, envelope.sender_device), (MessageType::PreKey, 1));
+
+            let message = PreKeySignalMessage::try_from(&envelope.ciphertext().unwrap()[..]).unwrap();
+
+            let plaintext = message_decrypt(
+                &CiphertextMessage::PreKeySignalMessage(message),
+                &bob_address,
+                &mut alice.session_store,
+                &mut alice.identity_store,
+                &mut alice.pre_key_store,
+                &mut alice.signed_pre_key_store,
+                &mut csprng,
+                None
+            ).await.unwrap();
+
+            assert_eq!(plaintext.len() % PADDING_BLOCK_SIZE, 0);
+            let decrypted = open_content(Some(envelope), plaintext).unwrap();
+            assert_eq!(decrypted.timestamp, Some(100));
+            assert_eq!(parse_session_reset(&decrypted.content).unwrap(), "message");
+            assert!(alice.session_store.has_session("bob"));
+            assert_eq!(bob.session_store.list()[0].established, Some(100));
+            assert!(parse_session_reset(&Content::new(message::TEXT, b"{\"message_id\": \"message\"}".to_vec())).is_err());
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn test_sealed_sender() {
+        async {
+            let mut csprng = OsRng;
+            let transport = Rc::new(MemoryTransport::new());
+            let mut alice = SyncableStore::register(hex::encode([1u8; 32]), transport.clone(), 1, None).unwrap();
+            let mut bob = SyncableStore::register(hex::encode([2u8; 32]), transport.clone(), 1, None).unwrap();
+            let alice_address = ProtocolAddress::new("alice".to_owned(), 1);
+
+            let (signed_pre_key_id, _) = rotate_signed_pre_key(&mut alice, RotationPolicy::default(), 0).await.unwrap();
+            publish_last_resort_bundle(&alice, signed_pre_key_id).await.unwrap();
+            let (_, _, body) = transport.requests().pop().unwrap();
+            let bundle: serde_json::Value = serde_json::from_str(&body.unwrap()).unwrap();
+            transport.respond("GET", "/protocol/bundles/alice/1/last-resort", bundle["bundle"].clone());
+
+            let pre_key_bundle = fetch_pre_key_bundle(&bob, &alice_address).await.unwrap();
+            process_prekey_bundle(&alice_address, &mut bob.session_store, &mut bob.identity_store, &pre_key_bundle, &mut csprng, None).await.unwrap();
+
+            // Bob's certificate, issued by a server that is signed by the trust root
+            let trust_root = KeyPair::generate(&mut csprng);
+            let server_key = KeyPair::generate(&mut csprng);
+            let server_certificate = ServerCertificate::new(1, server_key.public_key, &trust_root.private_key, &mut csprng).unwrap();
+            let bob_key = *bob.get_identity_key_pair(None).await.unwrap().identity_key();
+            let certificate = SenderCertificate::new("bob".to_owned(), None, *bob_key.public_key(), 1, 1000, server_certificate, &server_key.private_key, &mut csprng).unwrap();
+
+            let mallory = KeyPair::generate(&mut csprng);
+            assert!(validate_sender_certificate(&bob, &certificate, &trust_root.public_key, 100).await.is_ok());
+            assert_eq!(validate_sender_certificate(&bob, &certificate, &mallory.public_key, 100).await, Err(Error::InvalidSignature));
+            assert!(matches!(validate_sender_certificate(&alice, &certificate, &trust_root.public_key, 100).await, Err(Error::Malformed(_))));
+
+            let sealed = sealed_sender_encrypt(&alice_address, &certificate, b"hello", &mut bob.session_store, &mut bob.identity_store, None, &mut csprng).await.unwrap();
+            let message = Envelope::new(MessageType::Sealed, 0, Padding::None, &sealed).encode().unwrap();
+
+            // Sealed messages do not go through the regular decrypt
+            assert!(matches!(decrypt_message(&mut alice, &ProtocolAddress::new("bob".to_owned(), 1), &message).await, Err(Error::Malformed(_))));
+
+            // Expiry goes by the time of receipt, whatever the envelope claims
+            assert_eq!(unseal_message(&mut alice, &trust_root.public_key, &message, 1001).await.err(), Some(Error::InvalidSignature));
+
+            // Nor do we accept messages from ourselves
+            assert!(validate_sender(&alice, &certificate, &trust_root.public_key, 100).await.is_ok());
+            assert!(matches!(validate_sender(&bob, &certificate, &trust_root.public_key, 100).await, Err(Error::Protocol(_))));
+
+            let (address, ctext, envelope) = unseal_message(&mut alice, &trust_root.public_key, &message, 100).await.unwrap();
+            assert_eq!(address, ProtocolAddress::new("bob".to_owned(), 1));
+            assert_eq!(envelope.message_type, MessageType::Sealed);
+
+            let plaintext = message_decrypt(
+                &ctext,
+                &address,
+                &mut alice.session_store,
+                &mut alice.identity_store,
+                &mut alice.pre_key_store,
+                &mut alice.signed_pre_key_store,
+                &mut csprng,
+                None
+            ).await.unwrap();
+
+            assert_eq!(plaintext, b"hello");
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn test_group_messages() {
+        async {
+            let mut csprng = OsRng;
+            let transport = Rc::new(MemoryTransport::new());
+            let mut alice = SyncableStore::register(hex::encode([1u8; 32]), transport.clone(), 1, None).unwrap();
+            let mut bob = SyncableStore::register(hex::encode([2u8; 32]), transport.clone(), 1, None).unwrap();
+            let alice_address = ProtocolAddress::new("alice".to_owned(), 1);
+            let distribution_id = Uuid::new_v4();
+
+            let distribution_message = create_sender_key_distribution_message(&alice_address, distribution_id, &mut alice.sender_key_store, &mut csprng, None).await.unwrap();
+            process_sender_key_distribution_message(&alice_address, &distribution_message, &mut bob.sender_key_store, None).await.unwrap();
+
+            let short = encrypt_group_message(&mut alice, &alice_address, distribution_id, &Content::new(message::TEXT, b"hi".to_vec()), 100).await.unwrap();
+            let long = encrypt_group_message(&mut alice, &alice_address, distribution_id, &Content::new(message::TEXT, b"hello there".to_vec()), 100).await.unwrap();
+
+            // Group messages are padded just like pairwise ones
+            let envelope = Envelope::decode(&short).unwrap();
+            assert_eq!((envelope.message_type, envelope.sender_device, envelope.padding), (MessageType::SenderKey, 1, Padding::Block));
+            assert_eq!(envelope.ciphertext().unwrap().len(), Envelope::decode(&long).unwrap().ciphertext().unwrap().len());
+
+            let decrypted = decrypt_group_message(&mut bob, &alice_address, &short).await.unwrap();
+            assert_eq!(decrypted, Message::new(Content::new(message::TEXT, b"hi".to_vec()), 100));
+
+            assert!(matches!(decrypt_group_message(&mut bob, &ProtocolAddress::new("alice".to_owned(), 2), &long).await, Err(Error::Malformed(_))));
+            assert!(matches!(decrypt_message(&mut bob, &alice_address, &long).await, Err(Error::Malformed(_))));
+
+            // Messages from before the envelope are still accepted
+            let legacy = group_encrypt(&mut alice.sender_key_store, &alice_address, distribution_id, b"hello", &mut csprng, None).await.unwrap();
+            let decrypted = decrypt_group_message(&mut bob, &alice_address, &base64::encode(legacy.serialized())).await.unwrap();
+            assert_eq!(decrypted.content.body, b"hello");
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn test_session_resets() {
+        let mut resets = SessionResets::default();
+        let alice = ProtocolAddress::new("alice".to_owned(), 1);
+        let bob = ProtocolAddress::new("bob".to_owned(), 1);
+
+        assert!(resets.allow(&alice, 0));
+        assert!(resets.allow(&bob, 0));
+        assert!(!resets.allow(&alice, SESSION_RESET_INTERVAL - 1));
+        assert!(resets.allow(&alice, SESSION_RESET_INTERVAL));
+    }
 
     #[test]
     fn test_protocol() {
@@ -0,0 +1,109 @@
+extern crate base64;
+extern crate hex;
+
+use std::result::Result;
+
+use rand::rngs::OsRng;
+use libsignal_protocol::{IdentityKeyPair, PublicKey};
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::Algorithm;
+use crate::error::Error;
+
+pub const SHARED_KEY_VERSION: u8 = 1;
+
+/*
+ * Key material as it is shared with another user
+ *
+ * Always travels inside a `SignedSharedKey`, over a regular Signal session.
+ */
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct SharedKey {
+    pub key_id: String,
+    pub algorithm: String,
+    pub key: String,
+    pub sender: String,
+    pub recipient: String,
+    pub purpose: String
+}
+
+/*
+ * The payload is kept as the exact string that was signed, so that the signature does not
+ * depend on how the json happens to be serialized.
+ */
+#[derive(Deserialize, Serialize)]
+struct SignedSharedKey {
+    version: u8,
+    payload: String,
+    signature: String
+}
+
+impl SharedKey {
+    pub fn new(key_id: String, key: String, sender: String, recipient: String, purpose: String) -> Result<Self, Error> {
+        let key_bytes = hex::decode(&key).map_err(|_| Error::Malformed("Key is not in hex".to_string()))?;
+        let algorithm = Algorithm::for_key(&key_bytes)?;
+
+        Ok(SharedKey { key_id, algorithm: algorithm.name().to_string(), key, sender, recipient, purpose })
+    }
+
+    /*
+     * Sign with our identity key, returns the serialized envelope
+     */
+    pub fn sign(&self, key_pair: &IdentityKeyPair) -> Result<String, Error> {
+        let mut csprng = OsRng;
+
+        let payload = serde_json::to_string(self).map_err(|e| Error::Malformed(e.to_string()))?;
+        let signature = key_pair.private_key().calculate_signature(payload.as_bytes(), &mut csprng)?;
+
+        let signed = SignedSharedKey { version: SHARED_KEY_VERSION, payload, signature: base64::encode(signature) };
+
+        serde_json::to_string(&signed).map_err(|e| Error::Malformed(e.to_string()))
+    }
+
+    /*
+     * Parse an envelope and check it was signed by the given identity
+     */
+    pub fn verify(message: &[u8], public_key: &PublicKey) -> Result<Self, Error> {
+        let signed: SignedSharedKey = serde_json::from_slice(message).map_err(|_| Error::Malformed("Invalid shared key".to_string()))?;
+
+        if signed.version != SHARED_KEY_VERSION {
+            return Err(Error::Malformed(format!("Unsupported shared key version {}", signed.version)));
+        }
+
+        let signature = base64::decode(&signed.signature).map_err(|_| Error::Malformed("Invalid signature".to_string()))?;
+        if !public_key.verify_signature(signed.payload.as_bytes(), &signature)? {
+            return Err(Error::InvalidSignature);
+        }
+
+        let shared_key: SharedKey = serde_json::from_str(&signed.payload).map_err(|_| Error::Malformed("Invalid shared key".to_string()))?;
+        let key_bytes = hex::decode(&shared_key.key).map_err(|_| Error::Malformed("Key is not in hex".to_string()))?;
+
+        if Algorithm::for_key(&key_bytes)?.name() != shared_key.algorithm {
+            return Err(Error::Malformed("Key does not match the algorithm".to_string()));
+        }
+
+        Ok(shared_key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_verify() {
+        let mut csprng = OsRng;
+        let alice = IdentityKeyPair::generate(&mut csprng);
+        let mallory = IdentityKeyPair::generate(&mut csprng);
+
+        let shared_key = SharedKey::new("a".to_string(), hex::encode([1u8; 32]), "alice".to_string(), "bob".to_string(), "dataset".to_string()).unwrap();
+        let message = shared_key.sign(&alice).unwrap();
+
+        assert_eq!(shared_key.algorithm, "aes-256-gcm-siv");
+        assert_eq!(shared_key, SharedKey::verify(message.as_bytes(), alice.public_key()).unwrap());
+        assert_eq!(SharedKey::verify(message.as_bytes(), mallory.public_key()), Err(Error::InvalidSignature));
+
+        let tampered = message.replace("bob", "eve");
+        assert_eq!(SharedKey::verify(tampered.as_bytes(), alice.public_key()), Err(Error::InvalidSignature));
+    }
+}
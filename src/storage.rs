@@ -1,61 +1,349 @@
 extern crate base64;
 extern crate hex;
 
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::borrow::Cow;
+use std::rc::Rc;
+use std::result::Result;
+use std::convert::TryFrom;
 
+use async_trait::async_trait;
 use rand::rngs::OsRng;
+use rand::{CryptoRng, Rng};
 use libsignal_protocol::*;
-use libsignal_protocol::SessionStore;
 use uuid::Uuid;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
-use crate::utils::*;
 use crate::crypto::*;
+use crate::error::Error;
+use crate::message::Content;
+use crate::outbox::{Outbox, OutboxEntry};
+use crate::transport::Transport;
 
 /*
- * Simply (and unsafely) override some internal structs to enable
- * serialization of the private hashmaps.
+ * Protocol stores
  *
- * This is terrible, but much quicker than implementing the storage
- * traits. Not every operation needs hit the remote server (immediately)
- * anyways, as we can just sync it at intervals so that frequent mutations
+ * Same as the in-memory stores from libsignal, but with the hashmaps in reach so that
+ * they can be (de)serialized. Not every operation needs to hit the remote server
+ * (immediately) anyways, as we can just sync it at intervals so that frequent mutations
  * are buffered.
+ */
+#[derive(Clone, Default)]
+pub struct SyncableSessionStore {
+    sessions: HashMap<ProtocolAddress, SessionRecord>,
+    established: HashMap<ProtocolAddress, u64>,
+}
+
+/*
+ * Summary of a session, a session that is not active only holds archived states
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionInfo {
+    pub address: ProtocolAddress,
+    pub active: bool,
+    pub established: Option<u64>
+}
+
+impl SyncableSessionStore {
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let mut sessions: Vec<SessionInfo> = self.sessions.iter().map(|(address, record)| SessionInfo {
+            address: address.clone(),
+            active: record.has_current_session_state(),
+            established: self.established.get(address).cloned()
+        }).collect();
+        sessions.sort_by(|a, b| (a.address.name(), a.address.device_id()).cmp(&(b.address.name(), b.address.device_id())));
+
+        sessions
+    }
+
+    /*
+     * Whether we have an active session with any device of the user
+     */
+    pub fn has_session(&self, user_id: &str) -> bool {
+        self.sessions.iter().any(|(address, record)| address.name() == user_id && record.has_current_session_state())
+    }
+
+    /*
+     * Record when the current session with the address was set up, in ms
+     */
+    pub fn set_established(&mut self, address: &ProtocolAddress, timestamp: u64) {
+        self.established.insert(address.clone(), timestamp);
+    }
+
+    /*
+     * Forget the sessions with every device of the user, returns how many were removed
+     */
+    pub fn delete_sessions(&mut self, user_id: &str) -> usize {
+        let count = self.sessions.len();
+        self.sessions.retain(|address, _| address.name() != user_id);
+        self.established.retain(|address, _| address.name() != user_id);
+
+        count - self.sessions.len()
+    }
+
+    /*
+     * Archive the current sessions with every device of the user
+     *
+     * The previous states are kept so that messages that are still underway can be decrypted,
+     * the next message we send sets up a new session. Returns how many were archived.
+     */
+    pub fn archive_sessions(&mut self, user_id: &str) -> Result<usize, Error> {
+        let mut count = 0;
+
+        for (address, record) in self.sessions.iter_mut().filter(|(address, _)| address.name() == user_id) {
+            if record.has_current_session_state() {
+                record.archive_current_state()?;
+                self.established.remove(address);
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+#[async_trait(?Send)]
+impl SessionStore for SyncableSessionStore {
+    async fn load_session(&self, address: &ProtocolAddress, _ctx: Context) -> Result<Option<SessionRecord>, SignalProtocolError> {
+        Ok(self.sessions.get(address).cloned())
+    }
+
+    async fn store_session(&mut self, address: &ProtocolAddress, record: &SessionRecord, _ctx: Context) -> Result<(), SignalProtocolError> {
+        self.sessions.insert(address.clone(), record.clone());
+        Ok(())
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct SyncablePreKeyStore {
+    pre_keys: HashMap<PreKeyId, PreKeyRecord>,
+}
+
+#[async_trait(?Send)]
+impl PreKeyStore for SyncablePreKeyStore {
+    async fn get_pre_key(&self, prekey_id: PreKeyId, _ctx: Context) -> Result<PreKeyRecord, SignalProtocolError> {
+        self.pre_keys.get(&prekey_id).cloned().ok_or(SignalProtocolError::InvalidPreKeyId)
+    }
+
+    async fn save_pre_key(&mut self, prekey_id: PreKeyId, record: &PreKeyRecord, _ctx: Context) -> Result<(), SignalProtocolError> {
+        self.pre_keys.insert(prekey_id, record.clone());
+        Ok(())
+    }
+
+    async fn remove_pre_key(&mut self, prekey_id: PreKeyId, _ctx: Context) -> Result<(), SignalProtocolError> {
+        self.pre_keys.remove(&prekey_id);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct SyncableSignedPreKeyStore {
+    signed_pre_keys: HashMap<SignedPreKeyId, SignedPreKeyRecord>,
+}
+
+impl SyncableSignedPreKeyStore {
+    /*
+     * Ids and timestamps of all signed pre keys, oldest first
+     */
+    fn timestamps(&self) -> Result<Vec<(SignedPreKeyId, u64)>, SignalProtocolError> {
+        let mut timestamps = self.signed_pre_keys.iter()
+            .map(|(id, record)| Ok((*id, record.timestamp()?)))
+            .collect::<Result<Vec<_>, SignalProtocolError>>()?;
+        timestamps.sort_by_key(|(id, timestamp)| (*timestamp, *id));
+
+        Ok(timestamps)
+    }
+
+    /*
+     * The most recently generated signed pre key, as (id, timestamp)
+     */
+    pub fn current(&self) -> Result<Option<(SignedPreKeyId, u64)>, SignalProtocolError> {
+        Ok(self.timestamps()?.pop())
+    }
+
+    /*
+     * Remove signed pre keys that were replaced more than `grace_period` ms ago
+     *
+     * The current signed pre key is never removed.
+     */
+    pub fn prune(&mut self, now: u64, grace_period: u64) -> Result<Vec<SignedPreKeyId>, SignalProtocolError> {
+        let timestamps = self.timestamps()?;
+
+        let pruned: Vec<SignedPreKeyId> = timestamps.windows(2)
+            .filter(|pair| now.saturating_sub(pair[1].1) > grace_period)
+            .map(|pair| pair[0].0)
+            .collect();
+
+        for id in &pruned {
+            self.signed_pre_keys.remove(id);
+        }
+
+        Ok(pruned)
+    }
+
+    pub fn remove(&mut self, signed_pre_key_id: SignedPreKeyId) {
+        self.signed_pre_keys.remove(&signed_pre_key_id);
+    }
+}
+
+#[async_trait(?Send)]
+impl SignedPreKeyStore for SyncableSignedPreKeyStore {
+    async fn get_signed_pre_key(&self, signed_prekey_id: SignedPreKeyId, _ctx: Context) -> Result<SignedPreKeyRecord, SignalProtocolError> {
+        self.signed_pre_keys.get(&signed_prekey_id).cloned().ok_or(SignalProtocolError::InvalidSignedPreKeyId)
+    }
+
+    async fn save_signed_pre_key(&mut self, signed_prekey_id: SignedPreKeyId, record: &SignedPreKeyRecord, _ctx: Context) -> Result<(), SignalProtocolError> {
+        self.signed_pre_keys.insert(signed_prekey_id, record.clone());
+        Ok(())
+    }
+}
+
+/*
+ * How far we trust the identity key of a user
  *
- * TODO: Impl src/storage/traits.rs
+ * All devices of a user share the same identity key, so trust is tracked per user rather than
+ * per address. A key is verified when it matches the key the user was verified with, and
+ * changed when one of their devices presented a different key that has not been accepted yet.
  */
-#[allow(dead_code)]
-pub struct PubSessionStore {
-    pub sessions: HashMap<ProtocolAddress, SessionRecord>,
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IdentityStatus {
+    Unverified,
+    Verified,
+    Changed
 }
 
-#[allow(dead_code)]
-type PreKeyId = u32;
+impl IdentityStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IdentityStatus::Unverified => "unverified",
+            IdentityStatus::Verified => "verified",
+            IdentityStatus::Changed => "changed"
+        }
+    }
+}
 
-#[allow(dead_code)]
-pub struct PubPreKeyStore {
-    pub pre_keys: HashMap<PreKeyId, PreKeyRecord>,
+#[derive(Clone)]
+pub struct SyncableIdentityKeyStore {
+    key_pair: IdentityKeyPair,
+    id: u32,
+    known_keys: HashMap<ProtocolAddress, IdentityKey>,
+    verified_keys: HashMap<String, IdentityKey>,
+    // Written from is_trusted_identity, which only gets a shared reference
+    changed_keys: RefCell<HashMap<String, IdentityKey>>,
 }
 
-#[allow(dead_code)]
-type SignedPreKeyId = u32;
+impl SyncableIdentityKeyStore {
+    pub fn new(key_pair: IdentityKeyPair, id: u32) -> Self {
+        SyncableIdentityKeyStore {
+            key_pair,
+            id,
+            known_keys: HashMap::new(),
+            verified_keys: HashMap::new(),
+            changed_keys: RefCell::new(HashMap::new())
+        }
+    }
+
+    /*
+     * The identity key we know for any device of the user
+     */
+    pub fn user_identity(&self, user_id: &str) -> Option<IdentityKey> {
+        self.known_keys.iter()
+            .find(|(address, _)| address.name() == user_id)
+            .map(|(_, key)| *key)
+    }
 
-#[allow(dead_code)]
-pub struct PubSignedPreKeyStore {
-    pub signed_pre_keys: HashMap<SignedPreKeyId, SignedPreKeyRecord>,
+    pub fn identity_status(&self, user_id: &str) -> Option<IdentityStatus> {
+        let known_key = self.user_identity(user_id)?;
+
+        if self.changed_keys.borrow().contains_key(user_id) {
+            Some(IdentityStatus::Changed)
+        } else if self.verified_keys.get(user_id) == Some(&known_key) {
+            Some(IdentityStatus::Verified)
+        } else {
+            Some(IdentityStatus::Unverified)
+        }
+    }
+
+    /*
+     * Mark the identity key we currently know for the user as verified
+     */
+    pub fn mark_verified(&mut self, user_id: &str) -> Result<(), Error> {
+        let known_key = self.user_identity(user_id).ok_or_else(|| Error::IdentityNotFound(user_id.to_string()))?;
+        self.verified_keys.insert(user_id.to_string(), known_key);
+
+        Ok(())
+    }
+
+    /*
+     * Replace the known identity key of the user with the changed one
+     *
+     * The new key starts out unverified.
+     */
+    pub fn accept_new_identity(&mut self, user_id: &str) -> Result<(), Error> {
+        let new_key = self.changed_keys.borrow_mut().remove(user_id).ok_or_else(|| Error::IdentityNotFound(user_id.to_string()))?;
+
+        let addresses: Vec<ProtocolAddress> = self.known_keys.keys()
+            .filter(|address| address.name() == user_id)
+            .cloned()
+            .collect();
+        for address in addresses {
+            self.known_keys.insert(address, new_key);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl IdentityKeyStore for SyncableIdentityKeyStore {
+    async fn get_identity_key_pair(&self, _ctx: Context) -> Result<IdentityKeyPair, SignalProtocolError> {
+        Ok(self.key_pair)
+    }
+
+    async fn get_local_registration_id(&self, _ctx: Context) -> Result<u32, SignalProtocolError> {
+        Ok(self.id)
+    }
+
+    // Returns true if an existing identity was replaced
+    async fn save_identity(&mut self, address: &ProtocolAddress, identity: &IdentityKey, _ctx: Context) -> Result<bool, SignalProtocolError> {
+        match self.known_keys.insert(address.clone(), *identity) {
+            Some(previous) => Ok(previous != *identity),
+            None => Ok(false)
+        }
+    }
+
+    // Trust on first use, after that the key of the user may only change through accept_new_identity
+    async fn is_trusted_identity(&self, address: &ProtocolAddress, identity: &IdentityKey, _direction: Direction, _ctx: Context) -> Result<bool, SignalProtocolError> {
+        match self.user_identity(address.name()) {
+            Some(known_key) if known_key != *identity => {
+                self.changed_keys.borrow_mut().insert(address.name().to_string(), *identity);
+                Ok(false)
+            },
+            _ => Ok(true)
+        }
+    }
+
+    async fn get_identity(&self, address: &ProtocolAddress, _ctx: Context) -> Result<Option<IdentityKey>, SignalProtocolError> {
+        Ok(self.known_keys.get(address).cloned())
+    }
 }
 
-#[allow(dead_code)]
-struct PubIdentityKeyStore {
-    pub key_pair: IdentityKeyPair,
-    pub id: u32,
-    pub known_keys: HashMap<ProtocolAddress, IdentityKey>,
+#[derive(Clone, Default)]
+pub struct SyncableSenderKeyStore {
+    keys: HashMap<(ProtocolAddress, Uuid), SenderKeyRecord>,
 }
 
-#[allow(dead_code)]
-pub struct PubSenderKeyStore {
-    pub keys: HashMap<(Cow<'static, ProtocolAddress>, Uuid), SenderKeyRecord>,
+#[async_trait(?Send)]
+impl SenderKeyStore for SyncableSenderKeyStore {
+    async fn store_sender_key(&mut self, sender: &ProtocolAddress, distribution_id: Uuid, record: &SenderKeyRecord, _ctx: Context) -> Result<(), SignalProtocolError> {
+        self.keys.insert((sender.clone(), distribution_id), record.clone());
+        Ok(())
+    }
+
+    async fn load_sender_key(&mut self, sender: &ProtocolAddress, distribution_id: Uuid, _ctx: Context) -> Result<Option<SenderKeyRecord>, SignalProtocolError> {
+        Ok(self.keys.get(&(sender.clone(), distribution_id)).cloned())
+    }
 }
 
 
@@ -65,141 +353,330 @@ struct State {
     pre_keys: Vec<(u32, Vec<u8>)>, // HashMap<PreKeyId, PreKeyRecord>
     signed_pre_keys: Vec<(u32, Vec<u8>)>, // HashMap<SignedPreKeyId, SignedPreKeyRecord>
     key_pair: (Vec<u8>, Vec<u8>), // IdentityKeyPair
-    id: u32, // registration_id (not used)
+    id: u32, // registration_id
     known_keys: Vec<((String, u32), Vec<u8>)>, // HashMap<ProtocolAddress, IdentityKey>
-    keys: Vec<(((String, u32), String), Vec<u8>)>, // HashMap<(Cow<'static, ProtocolAddress>, Uuid), SenderKeyRecord>
+    keys: Vec<(((String, u32), String), Vec<u8>)>, // HashMap<(ProtocolAddress, Uuid), SenderKeyRecord>
+    verified_keys: Vec<(String, Vec<u8>)>, // HashMap<String, IdentityKey>
+    changed_keys: Vec<(String, Vec<u8>)>, // HashMap<String, IdentityKey>
+    established: Vec<((String, u32), u64)>, // HashMap<ProtocolAddress, u64>
+    outbox: Vec<OutboxEntry>,
+}
+
+/*
+ * Version 2 of the state, before the outbox
+ */
+#[derive(Deserialize)]
+struct StateV2 {
+    sessions: Vec<((String, u32), Vec<u8>)>,
+    pre_keys: Vec<(u32, Vec<u8>)>,
+    signed_pre_keys: Vec<(u32, Vec<u8>)>,
+    key_pair: (Vec<u8>, Vec<u8>),
+    id: u32,
+    known_keys: Vec<((String, u32), Vec<u8>)>,
+    keys: Vec<(((String, u32), String), Vec<u8>)>,
+    verified_keys: Vec<(String, Vec<u8>)>,
+    changed_keys: Vec<(String, Vec<u8>)>,
+    established: Vec<((String, u32), u64)>,
+}
+
+impl From<StateV2> for State {
+    fn from(state: StateV2) -> Self {
+        State {
+            sessions: state.sessions,
+            pre_keys: state.pre_keys,
+            signed_pre_keys: state.signed_pre_keys,
+            key_pair: state.key_pair,
+            id: state.id,
+            known_keys: state.known_keys,
+            keys: state.keys,
+            verified_keys: state.verified_keys,
+            changed_keys: state.changed_keys,
+            established: state.established,
+            outbox: vec![]
+        }
+    }
+}
+
+/*
+ * Version 1 of the state, before sessions kept track of when they were established
+ */
+#[derive(Deserialize)]
+struct StateV1 {
+    sessions: Vec<((String, u32), Vec<u8>)>,
+    pre_keys: Vec<(u32, Vec<u8>)>,
+    signed_pre_keys: Vec<(u32, Vec<u8>)>,
+    key_pair: (Vec<u8>, Vec<u8>),
+    id: u32,
+    known_keys: Vec<((String, u32), Vec<u8>)>,
+    keys: Vec<(((String, u32), String), Vec<u8>)>,
+    verified_keys: Vec<(String, Vec<u8>)>,
+    changed_keys: Vec<(String, Vec<u8>)>,
+}
+
+impl From<StateV1> for State {
+    fn from(state: StateV1) -> Self {
+        State {
+            sessions: state.sessions,
+            pre_keys: state.pre_keys,
+            signed_pre_keys: state.signed_pre_keys,
+            key_pair: state.key_pair,
+            id: state.id,
+            known_keys: state.known_keys,
+            keys: state.keys,
+            verified_keys: state.verified_keys,
+            changed_keys: state.changed_keys,
+            established: vec![],
+            outbox: vec![]
+        }
+    }
+}
+
+/*
+ * State as it was synced before it was versioned
+ */
+#[derive(Deserialize)]
+struct LegacyState {
+    sessions: Vec<((String, u32), Vec<u8>)>,
+    pre_keys: Vec<(u32, Vec<u8>)>,
+    signed_pre_keys: Vec<(u32, Vec<u8>)>,
+    key_pair: (Vec<u8>, Vec<u8>),
+    id: u32,
+    known_keys: Vec<((String, u32), Vec<u8>)>,
+    keys: Vec<(((String, u32), String), Vec<u8>)>,
+}
+
+impl From<LegacyState> for State {
+    fn from(state: LegacyState) -> Self {
+        State {
+            sessions: state.sessions,
+            pre_keys: state.pre_keys,
+            signed_pre_keys: state.signed_pre_keys,
+            key_pair: state.key_pair,
+            id: state.id,
+            known_keys: state.known_keys,
+            keys: state.keys,
+            verified_keys: vec![],
+            changed_keys: vec![],
+            established: vec![],
+            outbox: vec![]
+        }
+    }
+}
+
+/*
+ * Serialized state starts with a magic and a version. Legacy state starts with the number of
+ * sessions as a little endian u64 instead, which can never get anywhere near the magic.
+ */
+const STATE_MAGIC: &[u8; 7] = b"KXSTATE";
+const STATE_VERSION: u8 = 3;
+
+impl State {
+    fn decode(data: &[u8]) -> Result<Self, Error> {
+        let invalid = |_| Error::Malformed("Invalid state".to_string());
+
+        if !data.starts_with(STATE_MAGIC) {
+            let state: LegacyState = bincode::deserialize(data).map_err(invalid)?;
+            return Ok(state.into());
+        }
+
+        let body = &data[STATE_MAGIC.len()..];
+        match body.first() {
+            Some(1) => bincode::deserialize::<StateV1>(&body[1..]).map(State::from).map_err(invalid),
+            Some(2) => bincode::deserialize::<StateV2>(&body[1..]).map(State::from).map_err(invalid),
+            Some(&STATE_VERSION) => bincode::deserialize(&body[1..]).map_err(invalid),
+            version => Err(Error::Malformed(format!("Unsupported state version {:?}", version)))
+        }
+    }
+
+    fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut data = STATE_MAGIC.to_vec();
+        data.push(STATE_VERSION);
+        data.extend(bincode::serialize(self).map_err(|_| Error::Malformed("Cannot serialize state".to_string()))?);
+
+        Ok(data)
+    }
 }
 
 #[derive(Clone)]
 pub struct SyncableStore {
-    #[allow(dead_code)]
-    pub store: InMemSignalProtocolStore,
-    pub api_basepath: String,
-    #[allow(dead_code)]
+    pub session_store: SyncableSessionStore,
+    pub pre_key_store: SyncablePreKeyStore,
+    pub signed_pre_key_store: SyncableSignedPreKeyStore,
+    pub identity_store: SyncableIdentityKeyStore,
+    pub sender_key_store: SyncableSenderKeyStore,
+    pub outbox: Outbox,
+    pub transport: Rc<dyn Transport>,
+    pub device_id: u32,
     secret_key: Vec<u8>
 }
 
 /*
  * Syncable ProtocolStore
  *
- * Wrapper around the ProtocolStore, with some added functions to enable syncing the store
+ * Wrapper around the protocol stores, with some added functions to enable syncing the store
  * remotely. This implementation is not exactly safe from race conditions, as it may be killed
  * at any time before syncing the state. Rather than doing this properly we will just take the
  * easy road for now and embrace this property by only syncing sporadically to keep the overhead
  * low.
  *
  * This does require that any messages in limbo (or just all of them) need to be replayed.
+ *
+ * Every device of a user has its own store (and thus its own registration id, bundles and
+ * sessions), which is synced separately. Only the identity key is shared between devices.
  */
 impl SyncableStore {
-    pub fn register(secret_key: String, api_basepath: String) -> SyncableStore {
+    pub fn register(secret_key: String, transport: Rc<dyn Transport>, device_id: u32, identity_key: Option<IdentityKeyPair>) -> Result<SyncableStore, Error> {
         let mut csprng = OsRng;
-        let identity_key = IdentityKeyPair::generate(&mut csprng);
-        let store = InMemSignalProtocolStore::new(identity_key, 1).unwrap();
+        let identity_key = identity_key.unwrap_or_else(|| IdentityKeyPair::generate(&mut csprng));
 
-        SyncableStore {
-            store: store,
-            secret_key: hex::decode(secret_key).unwrap(),
-            api_basepath
-        }
+        Ok(SyncableStore {
+            session_store: SyncableSessionStore::default(),
+            pre_key_store: SyncablePreKeyStore::default(),
+            signed_pre_key_store: SyncableSignedPreKeyStore::default(),
+            identity_store: SyncableIdentityKeyStore::new(identity_key, gen_registration_id(&mut csprng)),
+            sender_key_store: SyncableSenderKeyStore::default(),
+            outbox: Outbox::default(),
+            secret_key: decode_secret_key(&secret_key)?,
+            transport,
+            device_id
+        })
     }
 
-    pub async fn new(secret_key: String, api_basepath: String) -> Self {
-        let json = request("GET".to_string(), format!("{}/protocol/sync", &api_basepath), None).await;
-
-        let secret = hex::decode(secret_key).unwrap();
-        let cstate: String = js_sys::Reflect::get(&json, &"state".into()).unwrap().as_string().unwrap();
-        let bytes = base64::decode(decrypt_custom(&cstate, &secret[..]).unwrap()).unwrap();
+    pub async fn new(secret_key: String, transport: Rc<dyn Transport>, device_id: u32) -> Result<Self, Error> {
+        let secret = decode_secret_key(&secret_key)?;
+        let cstate = fetch_state(&transport, device_id).await?;
 
-        let store = SyncableStore::deserialize(&bytes[..]).await;
-
-        SyncableStore {
-            store: store,
-            secret_key: secret,
-            api_basepath
+        if is_legacy(&cstate) {
+            return Err(Error::MigrationRequired("State".to_string()));
         }
-    }
 
-    #[allow(dead_code)]
-    pub async fn deserialize(data: &[u8]) -> InMemSignalProtocolStore {
-        let state: State = bincode::deserialize(data).unwrap();
+        let state = decrypt_custom_with_aad(&cstate, &secret[..], state_aad(device_id).as_bytes())?;
+        let bytes = base64::decode(state).map_err(|_| Error::Malformed("Invalid state".to_string()))?;
 
-        // Start with the identity_key, so that the store may be initialized
-        let public_key = IdentityKey::new(PublicKey::deserialize(&state.key_pair.0[..]).unwrap());
-        let private_key = PrivateKey::deserialize(&state.key_pair.1[..]).unwrap();
-        let identity_key = IdentityKeyPair::new(public_key, private_key);
-
-        let mut store = InMemSignalProtocolStore::new(identity_key, 1).unwrap();
+        SyncableStore::deserialize(&bytes[..], secret, transport, device_id)
+    }
 
-        for (k, v) in state.sessions {
-            let address = ProtocolAddress::new(k.0, k.1);
-            let record = SessionRecord::deserialize(&v[..]).unwrap();
+    /*
+     * Load a state that is still in the legacy format, and sync it back in the current one
+     *
+     * Legacy states are not bound to their device, so this is the only place they are accepted.
+     * Once synced the state is migrated, and from then on only loads through `new`.
+     */
+    pub async fn migrate(secret_key: String, transport: Rc<dyn Transport>, device_id: u32) -> Result<Self, Error> {
+        let secret = decode_secret_key(&secret_key)?;
+        let cstate = fetch_state(&transport, device_id).await?;
 
-            store.store_session(&address, &record, None).await.unwrap();
+        if !is_legacy(&cstate) {
+            return SyncableStore::new(secret_key, transport, device_id).await;
         }
 
-        for (k, v) in state.pre_keys {
-            let id: PreKeyId = k;
-            let record = PreKeyRecord::deserialize(&v[..]).unwrap();
+        let state = decrypt_custom(&cstate, &secret[..])?;
+        let bytes = base64::decode(state).map_err(|_| Error::Malformed("Invalid state".to_string()))?;
+        let store = SyncableStore::deserialize(&bytes[..], secret, transport, device_id)?;
 
-            store.save_pre_key(id, &record, None).await.unwrap();
-        }
+        store.sync(None).await?;
 
-        for (k, v) in state.signed_pre_keys {
-            let id: PreKeyId = k;
-            let record = SignedPreKeyRecord::deserialize(&v[..]).unwrap();
-
-            store.save_signed_pre_key(id, &record, None).await.unwrap();
-        }
-
-        for (k, v) in state.known_keys {
-            let address = ProtocolAddress::new(k.0, k.1);
-            let key = IdentityKey::new(PublicKey::deserialize(&v[..]).unwrap());
+        Ok(store)
+    }
 
-            store.save_identity(&address, &key, None).await.unwrap();
-        }
+    pub fn deserialize(data: &[u8], secret_key: Vec<u8>, transport: Rc<dyn Transport>, device_id: u32) -> Result<Self, Error> {
+        let state = State::decode(data)?;
 
-        for (k, v) in state.keys {
-            let address = ProtocolAddress::new(k.0.0, k.0.1);
-            let uuid = Uuid::parse_str(&k.1).unwrap();
-            let record = SenderKeyRecord::deserialize(&v[..]).unwrap();
+        // Start with the identity_key, so that the store may be initialized
+        let public_key = IdentityKey::new(PublicKey::deserialize(&state.key_pair.0[..])?);
+        let private_key = PrivateKey::deserialize(&state.key_pair.1[..])?;
+        let identity_key = IdentityKeyPair::new(public_key, private_key);
 
-            store.store_sender_key(&address, uuid, &record, None).await.unwrap();
-        }
+        let mut identity_store = SyncableIdentityKeyStore::new(identity_key, state.id);
+        identity_store.known_keys = state.known_keys.into_iter().map(|(k, v)| {
+            Ok((ProtocolAddress::new(k.0, k.1), IdentityKey::new(PublicKey::deserialize(&v[..])?)))
+        }).collect::<Result<_, SignalProtocolError>>()?;
+        identity_store.verified_keys = deserialize_user_keys(state.verified_keys)?;
+        identity_store.changed_keys = RefCell::new(deserialize_user_keys(state.changed_keys)?);
 
-        store
+        Ok(SyncableStore {
+            session_store: SyncableSessionStore {
+                sessions: state.sessions.into_iter().map(|(k, v)| {
+                    Ok((ProtocolAddress::new(k.0, k.1), SessionRecord::deserialize(&v[..])?))
+                }).collect::<Result<_, SignalProtocolError>>()?,
+                established: state.established.into_iter().map(|(k, v)| (ProtocolAddress::new(k.0, k.1), v)).collect()
+            },
+            pre_key_store: SyncablePreKeyStore {
+                pre_keys: state.pre_keys.into_iter().map(|(k, v)| {
+                    Ok((k, PreKeyRecord::deserialize(&v[..])?))
+                }).collect::<Result<_, SignalProtocolError>>()?
+            },
+            signed_pre_key_store: SyncableSignedPreKeyStore {
+                signed_pre_keys: state.signed_pre_keys.into_iter().map(|(k, v)| {
+                    Ok((k, SignedPreKeyRecord::deserialize(&v[..])?))
+                }).collect::<Result<_, SignalProtocolError>>()?
+            },
+            identity_store,
+            sender_key_store: SyncableSenderKeyStore {
+                keys: state.keys.into_iter().map(|(k, v)| {
+                    let uuid = Uuid::parse_str(&k.1).map_err(|_| Error::Malformed("Invalid distribution id".to_string()))?;
+                    Ok(((ProtocolAddress::new(k.0.0, k.0.1), uuid), SenderKeyRecord::deserialize(&v[..])?))
+                }).collect::<Result<_, Error>>()?
+            },
+            outbox: Outbox { entries: state.outbox },
+            secret_key,
+            transport,
+            device_id
+        })
     }
 
-    #[allow(dead_code)]
-    pub fn serialize(&self) -> Vec<u8> {
-        let session_store: PubSessionStore = unsafe { std::mem::transmute(self.store.session_store.clone()) };
-        let sessions = session_store.sessions.into_iter().map(|(k,v)| ((k.name().to_string(), k.device_id()), v.serialize().unwrap()) ).collect();
+    pub fn serialize(&self) -> Result<Vec<u8>, Error> {
+        let sessions = self.session_store.sessions.iter().map(|(k,v)| Ok(((k.name().to_string(), k.device_id()), v.serialize()?)) ).collect::<Result<_, SignalProtocolError>>()?;
+        let established = self.session_store.established.iter().map(|(k,v)| ((k.name().to_string(), k.device_id()), *v) ).collect();
+        let pre_keys = self.pre_key_store.pre_keys.iter().map(|(k,v)| Ok((*k, v.serialize()?)) ).collect::<Result<_, SignalProtocolError>>()?;
+        let signed_pre_keys = self.signed_pre_key_store.signed_pre_keys.iter().map(|(k,v)| Ok((*k, v.serialize()?)) ).collect::<Result<_, SignalProtocolError>>()?;
 
-        let pre_key_store: PubPreKeyStore = unsafe { std::mem::transmute(self.store.pre_key_store.clone()) };
-        let pre_keys = pre_key_store.pre_keys.into_iter().map(|(k,v)| (k, v.serialize().unwrap()) ).collect();
+        let identity_key = self.identity_store.key_pair.identity_key().public_key().serialize();
+        let private_key = self.identity_store.key_pair.private_key().serialize();
+        let known_keys = self.identity_store.known_keys.iter().map(|(k,v)| ((k.name().to_string(), k.device_id()), v.public_key().serialize().to_vec()) ).collect();
+        let verified_keys = serialize_user_keys(&self.identity_store.verified_keys);
+        let changed_keys = serialize_user_keys(&self.identity_store.changed_keys.borrow());
 
-        let signed_pre_key_store: PubSignedPreKeyStore = unsafe { std::mem::transmute(self.store.signed_pre_key_store.clone()) };
-        let signed_pre_keys = signed_pre_key_store.signed_pre_keys.into_iter().map(|(k,v)| (k, v.serialize().unwrap()) ).collect();
-
-        let identity_store: PubIdentityKeyStore = unsafe { std::mem::transmute(self.store.identity_store.clone()) };
-        let identity_key = identity_store.key_pair.identity_key().public_key().serialize();
-        let private_key = identity_store.key_pair.private_key().serialize();
-        let id = identity_store.id;
-        let known_keys = identity_store.known_keys.into_iter().map(|(k,v)| ((k.name().to_string(), k.device_id()), v.public_key().serialize().to_vec()) ).collect();
-
-        let sender_key_store: PubSenderKeyStore = unsafe { std::mem::transmute(self.store.sender_key_store.clone()) };
-        let keys = sender_key_store.keys.into_iter().map(|(k,v)| (((k.0.name().to_string(), k.0.device_id()), k.1.to_string()), v.serialize().unwrap()) ).collect();
+        let keys = self.sender_key_store.keys.iter().map(|(k,v)| Ok((((k.0.name().to_string(), k.0.device_id()), k.1.to_string()), v.serialize()?)) ).collect::<Result<_, SignalProtocolError>>()?;
 
         let state = State {
             sessions: sessions,
             pre_keys: pre_keys,
             signed_pre_keys: signed_pre_keys,
             key_pair: (identity_key.to_vec(), private_key),
-            id: id,
+            id: self.identity_store.id,
             known_keys: known_keys,
-            keys: keys
+            keys: keys,
+            verified_keys: verified_keys,
+            changed_keys: changed_keys,
+            established: established,
+            outbox: self.outbox.entries.clone()
         };
 
-        bincode::serialize(&state).unwrap()
+        state.encode()
+    }
+
+    /*
+     * Accept the changed identity key of a user
+     *
+     * Sessions with the old key are dropped, new ones are set up with the next message.
+     */
+    pub fn accept_new_identity(&mut self, user_id: &str) -> Result<(), Error> {
+        self.identity_store.accept_new_identity(user_id)?;
+        self.session_store.delete_sessions(user_id);
+
+        Ok(())
+    }
+
+    /*
+     * Queue a message for when the backend is reachable again, see `Outbox`
+     */
+    pub fn queue_message(&mut self, user_id: &str, content: &Content, now: u64) -> Result<String, Error> {
+        self.outbox.queue(&self.secret_key[..], user_id, content, now)
+    }
+
+    pub fn open_queued_message(&self, id: &str) -> Result<Content, Error> {
+        self.outbox.open(&self.secret_key[..], id)
     }
 
     /*
@@ -209,25 +686,134 @@ impl SyncableStore {
      * the state is restored to get all the chains back in order. This assumes that we don't
      * lose messages and are willing to replay a ton of them.
      */
-    pub async fn sync(&self, message_ids: Option<Vec<String>>) -> () {
-        let bytes = self.serialize();
-        let cstate = encrypt_custom(&base64::encode(&bytes), &self.secret_key[..]);
+    pub async fn sync(&self, message_ids: Option<Vec<String>>) -> Result<(), Error> {
+        let bytes = self.serialize()?;
+        let cstate = encrypt_custom_with_aad(&base64::encode(&bytes), &self.secret_key[..], None, state_aad(self.device_id).as_bytes())?;
         let payload = json!({
             "state": cstate,
             "message_ids": message_ids.unwrap_or(vec![])
         });
 
-        request("PUT".to_string(), format!("{}/protocol/sync", self.api_basepath), Some(payload.to_string())).await;
+        self.transport.request("PUT", &format!("/protocol/sync/{}", self.device_id), Some(payload.to_string())).await?;
+
+        Ok(())
+    }
+}
+
+async fn fetch_state(transport: &Rc<dyn Transport>, device_id: u32) -> Result<String, Error> {
+    let json = transport.request("GET", &format!("/protocol/sync/{}", device_id), None).await?;
+
+    json["state"].as_str()
+        .map(|cstate| cstate.to_string())
+        .ok_or_else(|| Error::Malformed("No state found for this device".to_string()))
+}
+
+/*
+ * The synced state is bound to its device, so that it cannot be restored as another device
+ */
+fn state_aad(device_id: u32) -> String {
+    format!("protocol/sync/{}", device_id)
+}
+
+fn serialize_user_keys(keys: &HashMap<String, IdentityKey>) -> Vec<(String, Vec<u8>)> {
+    keys.iter().map(|(k, v)| (k.clone(), v.public_key().serialize().to_vec())).collect()
+}
+
+fn deserialize_user_keys(keys: Vec<(String, Vec<u8>)>) -> Result<HashMap<String, IdentityKey>, SignalProtocolError> {
+    keys.into_iter().map(|(k, v)| Ok((k, IdentityKey::new(PublicKey::deserialize(&v[..])?)))).collect()
+}
+
+fn decode_secret_key(secret_key: &String) -> Result<Vec<u8>, Error> {
+    hex::decode(secret_key).map_err(|_| Error::Malformed("Secret key is not in hex".to_string()))
+}
+
+#[async_trait(?Send)]
+impl SessionStore for SyncableStore {
+    async fn load_session(&self, address: &ProtocolAddress, ctx: Context) -> Result<Option<SessionRecord>, SignalProtocolError> {
+        self.session_store.load_session(address, ctx).await
+    }
+
+    async fn store_session(&mut self, address: &ProtocolAddress, record: &SessionRecord, ctx: Context) -> Result<(), SignalProtocolError> {
+        self.session_store.store_session(address, record, ctx).await
+    }
+}
+
+#[async_trait(?Send)]
+impl PreKeyStore for SyncableStore {
+    async fn get_pre_key(&self, prekey_id: PreKeyId, ctx: Context) -> Result<PreKeyRecord, SignalProtocolError> {
+        self.pre_key_store.get_pre_key(prekey_id, ctx).await
+    }
+
+    async fn save_pre_key(&mut self, prekey_id: PreKeyId, record: &PreKeyRecord, ctx: Context) -> Result<(), SignalProtocolError> {
+        self.pre_key_store.save_pre_key(prekey_id, record, ctx).await
+    }
+
+    async fn remove_pre_key(&mut self, prekey_id: PreKeyId, ctx: Context) -> Result<(), SignalProtocolError> {
+        self.pre_key_store.remove_pre_key(prekey_id, ctx).await
+    }
+}
+
+#[async_trait(?Send)]
+impl SignedPreKeyStore for SyncableStore {
+    async fn get_signed_pre_key(&self, signed_prekey_id: SignedPreKeyId, ctx: Context) -> Result<SignedPreKeyRecord, SignalProtocolError> {
+        self.signed_pre_key_store.get_signed_pre_key(signed_prekey_id, ctx).await
+    }
+
+    async fn save_signed_pre_key(&mut self, signed_prekey_id: SignedPreKeyId, record: &SignedPreKeyRecord, ctx: Context) -> Result<(), SignalProtocolError> {
+        self.signed_pre_key_store.save_signed_pre_key(signed_prekey_id, record, ctx).await
+    }
+}
+
+#[async_trait(?Send)]
+impl IdentityKeyStore for SyncableStore {
+    async fn get_identity_key_pair(&self, ctx: Context) -> Result<IdentityKeyPair, SignalProtocolError> {
+        self.identity_store.get_identity_key_pair(ctx).await
+    }
+
+    async fn get_local_registration_id(&self, ctx: Context) -> Result<u32, SignalProtocolError> {
+        self.identity_store.get_local_registration_id(ctx).await
+    }
+
+    async fn save_identity(&mut self, address: &ProtocolAddress, identity: &IdentityKey, ctx: Context) -> Result<bool, SignalProtocolError> {
+        self.identity_store.save_identity(address, identity, ctx).await
+    }
+
+    async fn is_trusted_identity(&self, address: &ProtocolAddress, identity: &IdentityKey, direction: Direction, ctx: Context) -> Result<bool, SignalProtocolError> {
+        self.identity_store.is_trusted_identity(address, identity, direction, ctx).await
+    }
+
+    async fn get_identity(&self, address: &ProtocolAddress, ctx: Context) -> Result<Option<IdentityKey>, SignalProtocolError> {
+        self.identity_store.get_identity(address, ctx).await
+    }
+}
+
+#[async_trait(?Send)]
+impl SenderKeyStore for SyncableStore {
+    async fn store_sender_key(&mut self, sender: &ProtocolAddress, distribution_id: Uuid, record: &SenderKeyRecord, ctx: Context) -> Result<(), SignalProtocolError> {
+        self.sender_key_store.store_sender_key(sender, distribution_id, record, ctx).await
+    }
+
+    async fn load_sender_key(&mut self, sender: &ProtocolAddress, distribution_id: Uuid, ctx: Context) -> Result<Option<SenderKeyRecord>, SignalProtocolError> {
+        self.sender_key_store.load_sender_key(sender, distribution_id, ctx).await
     }
 }
 
+impl ProtocolStore for SyncableStore {}
+
+/*
+ * Registration ids are random 14 bit integers, same as the other Signal clients
+ */
+fn gen_registration_id<T>(csprng: &mut T) -> u32 where T: CryptoRng + Rng, {
+    csprng.gen_range(1, 16380)
+}
+
 
 #[derive(Clone, Deserialize, Serialize)]
 pub struct PreKeyBundleSerde {
     registration_id: u32, // registration_id: u32,
     device_id: u32, // device_id: u32,
     pre_key_id: Option<u32>, // pre_key_id: Option<PreKeyId>,
-    pre_key_public: Vec<u8>, // pre_key_public: Option<PublicKey>,
+    pre_key_public: Vec<u8>, // pre_key_public: Option<PublicKey>, empty when there is no pre key
     signed_pre_key_id: u32, // signed_pre_key_id: SignedPreKeyId,
     signed_pre_key_public: Vec<u8>, // signed_pre_key_public: PublicKey,
     signed_pre_key_signature: Vec<u8>, // signed_pre_key_signature: Vec<u8>,
@@ -235,68 +821,333 @@ pub struct PreKeyBundleSerde {
 }
 
 impl PreKeyBundleSerde {
-    pub fn deserialize(data: &[u8]) -> Self {
-        bincode::deserialize(data).unwrap()
+    pub fn deserialize(data: &[u8]) -> Result<Self, Error> {
+        bincode::deserialize(data).map_err(|_| Error::Malformed("Invalid pre key bundle".to_string()))
     }
 
-    pub fn serialize(&self) -> Vec<u8> {
-        bincode::serialize(self).unwrap()
+    pub fn serialize(&self) -> Result<Vec<u8>, Error> {
+        bincode::serialize(self).map_err(|_| Error::Malformed("Cannot serialize pre key bundle".to_string()))
     }
 }
 
-impl From<PreKeyBundle> for PreKeyBundleSerde {
-    fn from(bundle: PreKeyBundle) -> Self {
-        PreKeyBundleSerde {
-            registration_id: bundle.registration_id().unwrap(),
-            device_id: bundle.device_id().unwrap(),
-            pre_key_id: bundle.pre_key_id().unwrap(),
-            pre_key_public: bundle.pre_key_public().unwrap().unwrap().serialize().to_vec(),
-            signed_pre_key_id: bundle.signed_pre_key_id().unwrap(),
-            signed_pre_key_public: bundle.signed_pre_key_public().unwrap().serialize().to_vec(),
-            signed_pre_key_signature: bundle.signed_pre_key_signature().unwrap().to_vec(),
-            identity_key: bundle.identity_key().unwrap().public_key().serialize().to_vec(),
-        }
+impl TryFrom<PreKeyBundle> for PreKeyBundleSerde {
+    type Error = Error;
+
+    fn try_from(bundle: PreKeyBundle) -> Result<Self, Error> {
+        Ok(PreKeyBundleSerde {
+            registration_id: bundle.registration_id()?,
+            device_id: bundle.device_id()?,
+            pre_key_id: bundle.pre_key_id()?,
+            pre_key_public: bundle.pre_key_public()?.map(|key| key.serialize().to_vec()).unwrap_or_default(),
+            signed_pre_key_id: bundle.signed_pre_key_id()?,
+            signed_pre_key_public: bundle.signed_pre_key_public()?.serialize().to_vec(),
+            signed_pre_key_signature: bundle.signed_pre_key_signature()?.to_vec(),
+            identity_key: bundle.identity_key()?.public_key().serialize().to_vec(),
+        })
     }
 }
 
-impl From<PreKeyBundleSerde> for PreKeyBundle {
-    fn from(bundle: PreKeyBundleSerde) -> Self {
-        PreKeyBundle::new(
+impl TryFrom<PreKeyBundleSerde> for PreKeyBundle {
+    type Error = Error;
+
+    fn try_from(bundle: PreKeyBundleSerde) -> Result<Self, Error> {
+        // Last resort bundles only have a signed pre key
+        let pre_key = match (bundle.pre_key_id, bundle.pre_key_public.is_empty()) {
+            (Some(pre_key_id), false) => Some((pre_key_id, PublicKey::deserialize(&bundle.pre_key_public)?)),
+            (None, true) => None,
+            _ => return Err(Error::Malformed("Incomplete pre key".to_string()))
+        };
+
+        Ok(PreKeyBundle::new(
             bundle.registration_id,
             bundle.device_id,
-            Some((bundle.pre_key_id.unwrap(), PublicKey::deserialize(&bundle.pre_key_public).unwrap())),
+            pre_key,
             bundle.signed_pre_key_id,
-            PublicKey::deserialize(&bundle.signed_pre_key_public).unwrap(),
+            PublicKey::deserialize(&bundle.signed_pre_key_public)?,
             bundle.signed_pre_key_signature,
-            IdentityKey::new(PublicKey::deserialize(&bundle.identity_key).unwrap()),
-        ).unwrap()
+            IdentityKey::new(PublicKey::deserialize(&bundle.identity_key)?),
+        )?)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use libsignal_protocol::IdentityKeyStore;
     use futures_util::FutureExt;
+    use aes_gcm_siv::{Aes256GcmSiv, Key, Nonce};
+    use aes_gcm_siv::aead::{Aead, NewAead};
+    use crate::transport::MemoryTransport;
+
+    fn gen_bundle(storage: &mut SyncableStore) -> PreKeyBundle {
+        async {
+            let mut csprng = OsRng;
+
+            let pre_key_pair = KeyPair::generate(&mut csprng);
+            let signed_pre_key_pair = KeyPair::generate(&mut csprng);
+            let signed_pre_key_signature = storage.get_identity_key_pair(None).await.unwrap()
+                .private_key()
+                .calculate_signature(&signed_pre_key_pair.public_key.serialize(), &mut csprng).unwrap();
+
+            storage.save_pre_key(1, &PreKeyRecord::new(1, &pre_key_pair), None).await.unwrap();
+            storage.save_signed_pre_key(1, &SignedPreKeyRecord::new(1, 0, &signed_pre_key_pair, &signed_pre_key_signature), None).await.unwrap();
+
+            PreKeyBundle::new(
+                storage.get_local_registration_id(None).await.unwrap(),
+                storage.device_id,
+                Some((1, pre_key_pair.public_key)),
+                1,
+                signed_pre_key_pair.public_key,
+                signed_pre_key_signature.to_vec(),
+                *storage.get_identity_key_pair(None).await.unwrap().identity_key(),
+            ).unwrap()
+        }
+        .now_or_never()
+        .expect("sync")
+    }
 
     #[test]
     fn test_serde() {
         async {
-            let mut storage: SyncableStore = SyncableStore::register("".to_owned());
-            let key_pair = &storage.store.get_identity_key_pair(None).await.unwrap();
+            let storage: SyncableStore = SyncableStore::register("".to_owned(), Rc::new(MemoryTransport::new()), 1, None).unwrap();
+            let key_pair = &storage.get_identity_key_pair(None).await.unwrap();
             let identity_key = key_pair.identity_key();
 
-            let encoded = base64::encode(storage.serialize());
+            let encoded = base64::encode(storage.serialize().unwrap());
             let bytes = &base64::decode(encoded).unwrap();
 
-            let store = SyncableStore::deserialize(bytes).await;
+            let store = SyncableStore::deserialize(bytes, vec![], Rc::new(MemoryTransport::new()), 1).unwrap();
             let key_pair = store.get_identity_key_pair(None).await.unwrap();
             let roundtrip_identity_key = key_pair.identity_key();
 
             assert_eq!(identity_key, roundtrip_identity_key);
+            assert_eq!(storage.get_local_registration_id(None).await.unwrap(), store.get_local_registration_id(None).await.unwrap());
         }
         .now_or_never()
         .expect("sync")
     }
-}
 
+    #[test]
+    fn test_serde_bundle() {
+        let mut storage = SyncableStore::register("".to_owned(), Rc::new(MemoryTransport::new()), 1, None).unwrap();
+        let bundle = gen_bundle(&mut storage);
+
+        let serde = PreKeyBundleSerde::try_from(bundle).unwrap();
+        let roundtrip = PreKeyBundle::try_from(PreKeyBundleSerde::deserialize(&serde.serialize().unwrap()).unwrap()).unwrap();
+
+        assert_eq!(roundtrip.pre_key_id().unwrap(), Some(1));
+
+        // Without a one-time pre key
+        let last_resort = PreKeyBundleSerde { pre_key_id: None, pre_key_public: vec![], ..serde.clone() };
+        let roundtrip = PreKeyBundle::try_from(last_resort).unwrap();
+
+        assert_eq!(roundtrip.pre_key_id().unwrap(), None);
+        assert!(roundtrip.pre_key_public().unwrap().is_none());
+
+        let incomplete = PreKeyBundleSerde { pre_key_public: vec![], ..serde };
+        assert!(PreKeyBundle::try_from(incomplete).is_err());
+    }
+
+    #[test]
+    fn test_serde_session() {
+        async {
+            let mut csprng = OsRng;
+
+            let mut alice = SyncableStore::register("".to_owned(), Rc::new(MemoryTransport::new()), 1, None).unwrap();
+            let mut bob = SyncableStore::register("".to_owned(), Rc::new(MemoryTransport::new()), 1, None).unwrap();
+            let alice_address = ProtocolAddress::new("alice".to_owned(), 1);
+            let bob_address = ProtocolAddress::new("bob".to_owned(), 1);
+
+            let bundle = gen_bundle(&mut bob);
+            process_prekey_bundle(&bob_address, &mut alice.session_store, &mut alice.identity_store, &bundle, &mut csprng, None).await.unwrap();
+            let first = message_encrypt(b"hello", &bob_address, &mut alice.session_store, &mut alice.identity_store, None).await.unwrap();
+
+            // Both sides continue with their restored state
+            let mut alice = SyncableStore::deserialize(&alice.serialize().unwrap(), vec![], Rc::new(MemoryTransport::new()), 1).unwrap();
+            let second = message_encrypt(b"world", &bob_address, &mut alice.session_store, &mut alice.identity_store, None).await.unwrap();
+
+            let mut bob = SyncableStore::deserialize(&bob.serialize().unwrap(), vec![], Rc::new(MemoryTransport::new()), 1).unwrap();
+
+            for (ctext, expected) in vec![(first, b"hello"), (second, b"world")] {
+                let plaintext = message_decrypt(
+                    &ctext,
+                    &alice_address,
+                    &mut bob.session_store,
+                    &mut bob.identity_store,
+                    &mut bob.pre_key_store,
+                    &mut bob.signed_pre_key_store,
+                    &mut csprng,
+                    None
+                ).await.unwrap();
+
+                assert_eq!(&plaintext[..], expected);
+            }
+
+            let bob = SyncableStore::deserialize(&bob.serialize().unwrap(), vec![], Rc::new(MemoryTransport::new()), 1).unwrap();
+
+            assert!(bob.load_session(&alice_address, None).await.unwrap().is_some());
+            assert_eq!(bob.get_identity(&alice_address, None).await.unwrap(), Some(*alice.get_identity_key_pair(None).await.unwrap().identity_key()));
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn test_serde_legacy_state() {
+        let storage = SyncableStore::register("".to_owned(), Rc::new(MemoryTransport::new()), 1, None).unwrap();
+        let state = State::decode(&storage.serialize().unwrap()).unwrap();
+
+        // Bincode lays out structs as tuples, this is what was synced before versioning
+        let legacy = bincode::serialize(&(state.sessions, state.pre_keys, state.signed_pre_keys, state.key_pair, state.id, state.known_keys, state.keys)).unwrap();
+        let store = SyncableStore::deserialize(&legacy, vec![], Rc::new(MemoryTransport::new()), 1).unwrap();
+
+        assert_eq!(store.identity_store.id, storage.identity_store.id);
+
+        let mut unsupported = STATE_MAGIC.to_vec();
+        unsupported.push(STATE_VERSION + 1);
+        assert!(matches!(State::decode(&unsupported), Err(Error::Malformed(_))));
+    }
+
+    #[test]
+    fn test_identity_trust() {
+        async {
+            let mut csprng = OsRng;
+            let mut storage = SyncableStore::register("".to_owned(), Rc::new(MemoryTransport::new()), 1, None).unwrap();
+            let bob_1 = ProtocolAddress::new("bob".to_owned(), 1);
+            let bob_2 = ProtocolAddress::new("bob".to_owned(), 2);
+            let key = *IdentityKeyPair::generate(&mut csprng).identity_key();
+            let new_key = *IdentityKeyPair::generate(&mut csprng).identity_key();
+
+            assert_eq!(storage.identity_store.identity_status("bob"), None);
+            assert!(storage.is_trusted_identity(&bob_1, &key, Direction::Sending, None).await.unwrap());
+            storage.save_identity(&bob_1, &key, None).await.unwrap();
+            assert_eq!(storage.identity_store.identity_status("bob"), Some(IdentityStatus::Unverified));
+
+            storage.identity_store.mark_verified("bob").unwrap();
+            assert_eq!(storage.identity_store.identity_status("bob"), Some(IdentityStatus::Verified));
+
+            // Devices of the same user share their identity key
+            assert!(storage.is_trusted_identity(&bob_2, &key, Direction::Receiving, None).await.unwrap());
+            assert!(!storage.is_trusted_identity(&bob_2, &new_key, Direction::Receiving, None).await.unwrap());
+            assert_eq!(storage.identity_store.identity_status("bob"), Some(IdentityStatus::Changed));
+
+            // Trust state is synced
+            let mut storage = SyncableStore::deserialize(&storage.serialize().unwrap(), vec![], Rc::new(MemoryTransport::new()), 1).unwrap();
+            assert_eq!(storage.identity_store.identity_status("bob"), Some(IdentityStatus::Changed));
+
+            storage.accept_new_identity("bob").unwrap();
+            assert_eq!(storage.identity_store.identity_status("bob"), Some(IdentityStatus::Unverified));
+            assert_eq!(storage.get_identity(&bob_1, None).await.unwrap(), Some(new_key));
+            assert!(storage.is_trusted_identity(&bob_2, &new_key, Direction::Sending, None).await.unwrap());
+
+            assert_eq!(storage.accept_new_identity("bob"), Err(Error::IdentityNotFound("bob".to_string())));
+            assert_eq!(storage.identity_store.mark_verified("alice"), Err(Error::IdentityNotFound("alice".to_string())));
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn test_archive_sessions() {
+        async {
+            let mut csprng = OsRng;
+
+            let mut alice = SyncableStore::register("".to_owned(), Rc::new(MemoryTransport::new()), 1, None).unwrap();
+            let mut bob = SyncableStore::register("".to_owned(), Rc::new(MemoryTransport::new()), 1, None).unwrap();
+            let alice_address = ProtocolAddress::new("alice".to_owned(), 1);
+            let bob_address = ProtocolAddress::new("bob".to_owned(), 1);
+
+            let bundle = gen_bundle(&mut bob);
+            process_prekey_bundle(&bob_address, &mut alice.session_store, &mut alice.identity_store, &bundle, &mut csprng, None).await.unwrap();
+            alice.session_store.set_established(&bob_address, 100);
+            let first = message_encrypt(b"hello", &bob_address, &mut alice.session_store, &mut alice.identity_store, None).await.unwrap();
+            let late = message_encrypt(b"world", &bob_address, &mut alice.session_store, &mut alice.identity_store, None).await.unwrap();
+
+            assert!(alice.session_store.has_session("bob"));
+            assert_eq!(alice.session_store.list(), vec![SessionInfo { address: bob_address.clone(), active: true, established: Some(100) }]);
+
+            message_decrypt(&first, &alice_address, &mut bob.session_store, &mut bob.identity_store, &mut bob.pre_key_store, &mut bob.signed_pre_key_store, &mut csprng, None).await.unwrap();
+            assert_eq!(bob.session_store.archive_sessions("alice").unwrap(), 1);
+            assert!(!bob.session_store.has_session("alice"));
+
+            // Messages from the archived session can still be decrypted
+            let mut bob = SyncableStore::deserialize(&bob.serialize().unwrap(), vec![], Rc::new(MemoryTransport::new()), 1).unwrap();
+            let plaintext = message_decrypt(&late, &alice_address, &mut bob.session_store, &mut bob.identity_store, &mut bob.pre_key_store, &mut bob.signed_pre_key_store, &mut csprng, None).await.unwrap();
+
+            assert_eq!(&plaintext[..], b"world");
+            assert_eq!(bob.session_store.archive_sessions("alice").unwrap(), 0);
+
+            let mut alice = SyncableStore::deserialize(&alice.serialize().unwrap(), vec![], Rc::new(MemoryTransport::new()), 1).unwrap();
+            assert_eq!(alice.session_store.list()[0].established, Some(100));
+            assert_eq!(alice.session_store.delete_sessions("bob"), 1);
+            assert!(alice.session_store.list().is_empty());
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    #[test]
+    fn test_prune_signed_pre_keys() {
+        let mut csprng = OsRng;
+        let mut store = SyncableSignedPreKeyStore::default();
+
+        for (id, timestamp) in [(1, 0), (2, 100), (3, 200)] {
+            let key_pair = KeyPair::generate(&mut csprng);
+            store.signed_pre_keys.insert(id, SignedPreKeyRecord::new(id, timestamp, &key_pair, &[]));
+        }
+
+        assert_eq!(store.current().unwrap(), Some((3, 200)));
+
+        // Key 1 was replaced at 100, key 2 at 200
+        assert_eq!(store.prune(150, 100).unwrap(), Vec::<u32>::new());
+        assert_eq!(store.prune(250, 100).unwrap(), vec![1]);
+        assert_eq!(store.prune(1000, 100).unwrap(), vec![2]);
+        assert_eq!(store.current().unwrap(), Some((3, 200)));
+    }
+
+    #[test]
+    fn test_sync() {
+        async {
+            let transport = Rc::new(MemoryTransport::new());
+            let secret_key = hex::encode([1u8; 32]);
+
+            let mut storage = SyncableStore::register(secret_key.clone(), transport.clone(), 2, None).unwrap();
+            let content = Content::new(crate::message::TEXT, b"hello".to_vec());
+            let queued = storage.queue_message("bob", &content, 0).unwrap();
+            storage.sync(Some(vec!["message".to_owned()])).await.unwrap();
+
+            let (method, path, _) = transport.requests().pop().unwrap();
+            assert_eq!((method.as_str(), path.as_str()), ("PUT", "/protocol/sync/2"));
+
+            let store = SyncableStore::new(secret_key.clone(), transport.clone(), 2).await.unwrap();
+
+            assert_eq!(
+                storage.get_identity_key_pair(None).await.unwrap().identity_key(),
+                store.get_identity_key_pair(None).await.unwrap().identity_key()
+            );
+            assert_eq!(store.open_queued_message(&queued).unwrap(), content);
+            assert!(SyncableStore::new(hex::encode([2u8; 32]), transport.clone(), 2).await.is_err());
+            assert!(SyncableStore::new(hex::encode([1u8; 32]), transport.clone(), 3).await.is_err());
+
+            // The state of one device cannot be restored as another
+            let (_, _, body) = transport.requests().into_iter().find(|(method, _, _)| method == "PUT").unwrap();
+            transport.respond("GET", "/protocol/sync/3", serde_json::from_str(&body.unwrap()).unwrap());
+
+            assert!(matches!(SyncableStore::new(secret_key.clone(), transport.clone(), 3).await, Err(Error::DecryptFailed(_))));
+
+            // A legacy state only loads through an explicit migration, which syncs it right back
+            let nonce = gen_nonce(&mut OsRng);
+            let bytes = Aes256GcmSiv::new(Key::from_slice(&[1u8; 32])).encrypt(Nonce::from_slice(&nonce), base64::encode(storage.serialize().unwrap()).as_bytes()).unwrap();
+            transport.respond("GET", "/protocol/sync/2", json!({"state": format!("{}:{}", base64::encode(nonce), base64::encode(bytes))}));
+
+            assert!(matches!(SyncableStore::new(secret_key.clone(), transport.clone(), 2).await, Err(Error::MigrationRequired(_))));
+
+            let migrated = SyncableStore::migrate(secret_key.clone(), transport.clone(), 2).await.unwrap();
+            assert_eq!(migrated.open_queued_message(&queued).unwrap(), content);
+
+            let store = SyncableStore::new(secret_key, transport, 2).await.unwrap();
+            assert_eq!(store.open_queued_message(&queued).unwrap(), content);
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+}
@@ -0,0 +1,152 @@
+#[cfg(any(test, feature = "memory-transport"))]
+use std::{cell::RefCell, collections::HashMap};
+
+use async_trait::async_trait;
+use serde_json::Value;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{Request, RequestInit, RequestCredentials, RequestMode, Response};
+
+use crate::error::Error;
+use crate::utils::*;
+
+/*
+ * Transport
+ *
+ * Everything that talks to the backend goes through here. Paths are relative to the api
+ * basepath, and the response is the decoded json body (or null when there is none).
+ */
+#[async_trait(?Send)]
+pub trait Transport {
+    async fn request(&self, method: &str, path: &str, body: Option<String>) -> Result<Value, Error>;
+}
+
+/*
+ * Default transport, using the fetch api of the browser
+ */
+pub struct FetchTransport {
+    api_basepath: String,
+    csrf_cookie: String
+}
+
+impl FetchTransport {
+    pub fn new(api_basepath: String) -> Self {
+        FetchTransport {
+            api_basepath,
+            csrf_cookie: "_mycelium_csrf_token".to_string()
+        }
+    }
+
+    /*
+     * Use the basepath as given from JS, or fall back to the one set at build time
+     */
+    pub fn from_js(api_basepath: &JsValue) -> Self {
+        let basepath = if !api_basepath.is_undefined() && api_basepath.is_string() {
+            api_basepath.as_string().unwrap()
+        } else {
+            option_env!("API_BASEPATH").unwrap_or("http://localhost:5000").to_string()
+        };
+
+        FetchTransport::new(basepath)
+    }
+
+    pub fn with_csrf_cookie(mut self, name: String) -> Self {
+        self.csrf_cookie = name;
+        self
+    }
+}
+
+#[async_trait(?Send)]
+impl Transport for FetchTransport {
+    async fn request(&self, method: &str, path: &str, body: Option<String>) -> Result<Value, Error> {
+        let url = format!("{}{}", self.api_basepath, path);
+        let mut opts = RequestInit::new();
+
+        opts.method(method);
+        opts.mode(RequestMode::Cors);
+        opts.credentials(RequestCredentials::Include);
+
+        if let Some(body) = body {
+            opts.body(Some(&body.into()));
+        }
+
+        let request = Request::new_with_str_and_init(&url, &opts)
+            .map_err(|_| Error::Network(format!("Invalid request: {} {}", method, url)))?;
+
+        let headers = request.headers();
+        headers.set("Content-Type", "application/json").map_err(|_| Error::Network("Cannot set headers".to_string()))?;
+        headers.set("Accept", "application/json").map_err(|_| Error::Network("Cannot set headers".to_string()))?;
+
+        if method != "GET" {
+            headers.set("X-CSRF-Token", get_cookie(&self.csrf_cookie)?.as_str()).map_err(|_| Error::Network("Cannot set headers".to_string()))?;
+        }
+
+        let window = web_sys::window().ok_or_else(|| Error::Network("No window available".to_string()))?;
+        let resp: Response = JsFuture::from(window.fetch_with_request(&request)).await
+            .map_err(|_| Error::Network(format!("Request failed: {} {}", method, url)))?
+            .dyn_into()
+            .map_err(|_| Error::Network(format!("Invalid response: {} {}", method, url)))?;
+
+        if !resp.ok() {
+            return Err(Error::Http(resp.status(), format!("{} {}", method, url)));
+        }
+
+        let text = JsFuture::from(resp.text().map_err(|_| Error::Network(format!("Invalid response: {} {}", method, url)))?).await
+            .map_err(|_| Error::Network(format!("Invalid response: {} {}", method, url)))?
+            .as_string()
+            .unwrap_or_default();
+
+        if text.is_empty() {
+            Ok(Value::Null)
+        } else {
+            serde_json::from_str(&text).map_err(|e| Error::Malformed(e.to_string()))
+        }
+    }
+}
+
+/*
+ * In-memory transport, for testing without a browser
+ *
+ * Responses can be set up per method and path. Anything that is PUT is kept around and
+ * returned on subsequent GET requests to the same path, which is enough to mimic syncing.
+ * Only built for tests, or with the "memory-transport" feature.
+ */
+#[cfg(any(test, feature = "memory-transport"))]
+#[derive(Default)]
+pub struct MemoryTransport {
+    responses: RefCell<HashMap<(String, String), Value>>,
+    requests: RefCell<Vec<(String, String, Option<String>)>>
+}
+
+#[cfg(any(test, feature = "memory-transport"))]
+impl MemoryTransport {
+    pub fn new() -> Self {
+        MemoryTransport::default()
+    }
+
+    pub fn respond(&self, method: &str, path: &str, response: Value) {
+        self.responses.borrow_mut().insert((method.to_string(), path.to_string()), response);
+    }
+
+    pub fn requests(&self) -> Vec<(String, String, Option<String>)> {
+        self.requests.borrow().clone()
+    }
+}
+
+#[cfg(any(test, feature = "memory-transport"))]
+#[async_trait(?Send)]
+impl Transport for MemoryTransport {
+    async fn request(&self, method: &str, path: &str, body: Option<String>) -> Result<Value, Error> {
+        self.requests.borrow_mut().push((method.to_string(), path.to_string(), body.clone()));
+
+        if method == "PUT" {
+            if let Some(body) = body {
+                let value: Value = serde_json::from_str(&body).map_err(|e| Error::Malformed(e.to_string()))?;
+                self.responses.borrow_mut().insert(("GET".to_string(), path.to_string()), value);
+            }
+        }
+
+        Ok(self.responses.borrow().get(&(method.to_string(), path.to_string())).cloned().unwrap_or(Value::Null))
+    }
+}
@@ -1,15 +1,17 @@
-use wasm_bindgen::prelude::*;
-
 use wasm_bindgen::JsCast;
-use wasm_bindgen_futures::*;
-use web_sys::{Request, RequestInit, RequestCredentials, RequestMode, Response, HtmlDocument};
+use web_sys::HtmlDocument;
+
+use crate::error::Error;
 
-fn document() -> HtmlDocument {
-    web_sys::window().unwrap().document().unwrap().dyn_into::<HtmlDocument>().unwrap()
+fn document() -> Result<HtmlDocument, Error> {
+    web_sys::window()
+        .and_then(|window| window.document())
+        .and_then(|document| document.dyn_into::<HtmlDocument>().ok())
+        .ok_or_else(|| Error::Network("No document available".to_string()))
 }
 
-pub fn get_cookie(name: &str) -> String {
-    let cookies = document().cookie().unwrap();
+pub fn get_cookie(name: &str) -> Result<String, Error> {
+    let cookies = document()?.cookie().map_err(|_| Error::Network("Cannot read cookies".to_string()))?;
     let value = cookies
         .split(';')
         .find_map(|kv|
@@ -26,37 +28,7 @@ pub fn get_cookie(name: &str) -> String {
         );
 
     match value {
-        Some(v) => v,
-        None => "".to_owned()
-    }
-}
-
-pub async fn request(method: String, url: String, payload: Option<String>) -> JsValue {
-    let mut opts = RequestInit::new();
-
-    opts.method(&method);
-    opts.mode(RequestMode::Cors);
-    opts.credentials(RequestCredentials::Include);
-
-    if payload.is_some() {
-        opts.body(Some(&payload.unwrap().into()));
+        Some(v) => Ok(v),
+        None => Ok("".to_owned())
     }
-
-    let request = Request::new_with_str_and_init(&url, &opts).unwrap();
-
-    let headers = request.headers();
-    headers.set("Content-Type", "application/json").unwrap();
-    headers.set("Accept", "application/json").unwrap();
-
-    if method != "GET" {
-        headers.set("X-CSRF-Token", get_cookie("_mycelium_csrf_token").as_str()).unwrap();
-    }
-
-    let window = web_sys::window().unwrap();
-    let resp_value = JsFuture::from(window.fetch_with_request(&request)).await.unwrap();
-
-    let resp: Response = resp_value.dyn_into().unwrap();
-    let json = JsFuture::from(resp.json().unwrap()).await.unwrap();
-
-    json
 }